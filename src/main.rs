@@ -1,100 +1,3248 @@
-use git2::{BranchType, Error, Repository};
+mod template;
+mod width;
+
+use git2::{
+    BranchType, Delta, Error, ErrorClass, ErrorCode, Oid, Reference, Repository, RepositoryState,
+    SubmoduleIgnore, SubmoduleStatus,
+};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{IsTerminal, Read, Write};
 use std::process;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use template::{ColorDepth, Template, Vocabulary};
+
+#[derive(Default)]
+struct Options {
+    recent: Option<usize>,
+    include_assume_unchanged: bool,
+    last_commit: bool,
+    verify_signature: bool,
+    assert_readonly: bool,
+    merged_check: bool,
+    since_fork: bool,
+    all_remotes: bool,
+    upstream_when_diverged: bool,
+    strip_prefix: Option<String>,
+    // Similarity percentage for git status's rename detection
+    rename_threshold: Option<usize>,
+    dotfiles: bool,
+    fast_clean: bool,
+    exit_code_divergence: bool,
+    // --by-dir: how many leading directories to group changes by
+    by_dir: Option<usize>,
+    branch_cache: bool,
+    // --action-words: what {action} says when behind, ahead, or both
+    action_words: Option<[String; 3]>,
+    // --base/--target: compare these two revisions instead of the working tree
+    base: Option<String>,
+    target: Option<String>,
+    // --stale-fetch, in seconds
+    stale_fetch: Option<i64>,
+    prefix: String,
+    suffix: String,
+    print_timings_json: bool,
+    timings_file: Option<String>,
+    untracked_files: Option<String>,
+    format: Option<Template>,
+    format_clean: Option<Template>,
+    submodules: bool,
+    compare_default: bool,
+    pathspecs: Vec<String>,
+    // --exclude patterns, relative to the repository root
+    excludes: Vec<String>,
+    strict: bool,
+    unpushed: bool,
+    ascii: bool,
+    // Set by --stash or --stash-symbol
+    stash_symbol: Option<String>,
+    // --stash-scope current counts only stashes made on the checked-out branch
+    stash_current_branch: bool,
+    stash_message_length: Option<usize>,
+    warn_skip_worktree: bool,
+    divergence: DivergenceStyle,
+    quiet: bool,
+    status_hook: Option<String>,
+    print_width: bool,
+    max_width: Option<usize>,
+    show_zeros: bool,
+    marker_order: Option<String>,
+    // Between components of the default layout; None means a single space
+    separator: Option<String>,
+    color: ColorMode,
+    // Write the result here instead of stdout
+    output_file: Option<String>,
+}
+
+// When `{color:<name>}` tokens emit escape codes
+#[derive(Default, Clone, Copy)]
+enum ColorMode {
+    // Only when stdout is a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// How {divergence} renders ahead/behind counts
+#[derive(Default, Clone, Copy)]
+enum DivergenceStyle {
+    // `↑3↓2`, leaving out zero counts
+    #[default]
+    Arrows,
+    // `+3/-2`, always both counts
+    Signed,
+}
+
+impl Options {
+    // Truecolor needs COLORTERM (or a `-direct` TERM) to vouch for it unless forced with
+    // --color always; otherwise 24-bit colors fall back to the 256-color palette
+    fn color_depth(&self) -> ColorDepth {
+        let truecolor = || {
+            env::var("COLORTERM").is_ok_and(|value| value == "truecolor" || value == "24bit")
+                || env::var("TERM").is_ok_and(|term| term.ends_with("-direct"))
+        };
+        match self.color {
+            ColorMode::Auto if self.output_file.is_some() || !std::io::stdout().is_terminal() => {
+                ColorDepth::Off
+            }
+            ColorMode::Auto if truecolor() => ColorDepth::TrueColor,
+            ColorMode::Auto => ColorDepth::Ansi256,
+            ColorMode::Always => ColorDepth::TrueColor,
+            ColorMode::Never => ColorDepth::Off,
+        }
+    }
+
+    fn template_uses(&self, name: &str) -> bool {
+        [&self.format, &self.format_clean].iter().any(|template| {
+            template
+                .as_ref()
+                .is_some_and(|template| template.uses(name))
+        })
+    }
+
+    fn symbols(&self) -> &'static SymbolSet {
+        if self.ascii {
+            &ASCII_SYMBOLS
+        } else {
+            &UNICODE_SYMBOLS
+        }
+    }
+}
+
+// Every marker gitstatus prints, so terminals without the glyphs can swap in plain text
+struct SymbolSet {
+    clean: &'static str,
+    conflict: &'static str,
+    updated: &'static str,
+    deleted: &'static str,
+    untracked: &'static str,
+    incomplete: &'static str,
+    unpushed: &'static str,
+    ahead: &'static str,
+    behind: &'static str,
+    default_upstream: &'static str,
+    stale_fetch: &'static str,
+    merged: &'static str,
+    since_fork: &'static str,
+    fetched: &'static str,
+    // Marks text cut short
+    ellipsis: &'static str,
+}
+
+const UNICODE_SYMBOLS: SymbolSet = SymbolSet {
+    clean: "✓",
+    conflict: "!",
+    updated: "+",
+    deleted: "x",
+    untracked: "n",
+    incomplete: "?",
+    unpushed: "⇡",
+    ahead: "↑",
+    behind: "↓",
+    default_upstream: "…",
+    stale_fetch: "⟳",
+    merged: "✂",
+    since_fork: "Δ",
+    fetched: "⇣",
+    ellipsis: "…",
+};
+
+// --ascii: ✓ -> OK, + -> M, x -> D, n -> U, ⇡ -> ^, ↑ -> >, ↓ -> <, … -> ..., ⟳ -> *,
+// ✂ -> m, Δ -> d, ⇣ -> v; the rest are already ASCII
+const ASCII_SYMBOLS: SymbolSet = SymbolSet {
+    clean: "OK",
+    conflict: "!",
+    updated: "M",
+    deleted: "D",
+    untracked: "U",
+    incomplete: "?",
+    unpushed: "^",
+    ahead: ">",
+    behind: "<",
+    default_upstream: "...",
+    stale_fetch: "*",
+    merged: "m",
+    since_fork: "d",
+    fetched: "v",
+    ellipsis: "...",
+};
+
+// What a GitStatus built with Default renders with
+impl Default for &'static SymbolSet {
+    fn default() -> Self {
+        &UNICODE_SYMBOLS
+    }
+}
+
+// Conflicts, modified, deleted, untracked: the order markers appear in by default. An
+// `s` anywhere in --marker-order moves the stash marker in among them
+const MARKER_ORDER: &str = "cmdu";
+
+#[derive(Default)]
+struct ChangesSummary {
+    conflicts: usize,
+    updated: usize,
+    deleted: usize,
+    untracked: usize,
+    // The untracked entries that are whole directories, which git collapses to `dir/`
+    // unless untracked files are listed individually
+    untracked_dirs: usize,
+    // Index changes ready to commit; not part of the markers or of being clean
+    staged: usize,
+    // Worktree-side modifications, typechanges and deletions, whether or not the index
+    // also changed. The markers only count entries with an unchanged index side
+    unstaged: usize,
+    // Lines in the porcelain output, every kind of entry included
+    entries: Option<usize>,
+    // Reported clean by --fast-clean without running git status
+    fast_clean: bool,
+    // Changed paths per directory prefix, for --by-dir
+    by_dir: Vec<(String, usize)>,
+    // Some directories couldn't be read, so the counts only cover the readable parts
+    incomplete: bool,
+}
+
+impl ChangesSummary {
+    fn is_clean(&self) -> bool {
+        self.conflicts == 0 && self.updated == 0 && self.deleted == 0 && self.untracked == 0
+    }
+
+    // Markers follow `order`, a permutation of MARKER_ORDER. With `show_zeros` every
+    // marker appears, zero or not, so the segment keeps the same shape in a clean repo
+    // and the clean symbol is never used. Otherwise the clean symbol stands where the
+    // first change marker would have been, on either side of an inline `stash`
+    fn format(
+        &self,
+        symbols: &SymbolSet,
+        show_zeros: bool,
+        order: &str,
+        stash: Option<&str>,
+    ) -> String {
+        let mut message = String::new();
+        let mut clean_at = None;
+        let mut changed = false;
+        for marker in order.chars() {
+            let (symbol, count) = match marker {
+                'c' => (symbols.conflict, self.conflicts),
+                'm' => (symbols.updated, self.updated),
+                'd' => (symbols.deleted, self.deleted),
+                'u' => (symbols.untracked, self.untracked),
+                's' => {
+                    message += stash.unwrap_or_default();
+                    continue;
+                }
+                _ => continue,
+            };
+            clean_at.get_or_insert(message.len());
+            if show_zeros || count > 0 {
+                message += &format!("{}{}", symbol, count);
+                changed = true;
+            }
+        }
+        if !changed {
+            message.insert_str(clean_at.unwrap_or_default(), symbols.clean);
+        }
+        if self.incomplete {
+            message += symbols.incomplete;
+        }
+
+        message
+    }
+}
+
+// Wall-clock time spent in each phase, for tracking prompt latency across runs
+struct Timings {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    // Porcelain entries git status reported, None when it didn't run or failed
+    entries: Option<usize>,
+    // --fast-clean's checks stood in for git status
+    fast_clean: bool,
+}
+
+impl Timings {
+    fn new() -> Self {
+        let now = Instant::now();
+        Timings {
+            start: now,
+            last: now,
+            phases: Vec::new(),
+            entries: None,
+            fast_clean: false,
+        }
+    }
+
+    fn record(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    // Flat object of microsecond durations, one `<phase>_us` key per phase plus
+    // `total_us`, then `entries` (null without a git status run) and `fast_clean`
+    fn to_json(&self) -> String {
+        let mut fields: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(phase, duration)| format!("\"{}_us\":{}", phase, duration.as_micros()))
+            .collect();
+        fields.push(format!(
+            "\"total_us\":{}",
+            (self.last - self.start).as_micros()
+        ));
+        fields.push(format!(
+            "\"entries\":{}",
+            self.entries
+                .map_or("null".to_string(), |entries| entries.to_string())
+        ));
+        fields.push(format!("\"fast_clean\":{}", self.fast_clean));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+// What a prompt redraw almost never changes, so --branch-cache can reuse it
+struct BranchInfo {
+    branch: Option<String>,
+    upstream: Option<String>,
+    upstream_branch: Option<String>,
+}
+
+// Progress of an in-progress rebase, `git am`, or cherry-pick or revert sequence, from
+// the state files git keeps in the git dir
+struct Rebase {
+    // `AM` when applying a mailbox, which resolves differently from a rebase, and
+    // `CHERRY-PICK` or `REVERT` for a sequence of those
+    operation: &'static str,
+    // Branch being rebased, None when rebasing a detached HEAD
+    branch: Option<String>,
+    step: Option<usize>,
+    total: Option<usize>,
+    // What the commits are being replayed onto, as a branch name when one points there
+    onto: Option<String>,
+}
+
+impl Rebase {
+    // e.g. `|REBASE 2/5`, `|AM 3/10` or `|CHERRY-PICK 1/2`, appended directly to the
+    // branch name
+    fn label(&self) -> String {
+        match (self.step, self.total) {
+            (Some(step), Some(total)) => format!("|{} {}/{}", self.operation, step, total),
+            _ => format!("|{}", self.operation),
+        }
+    }
+}
+
+// Everything gitstatus knows about the repository, gathered before any formatting
+#[derive(Default)]
+struct GitStatus {
+    symbols: &'static SymbolSet,
+    repo: String,
+    // Absolute worktree root, empty for a bare repository, and absolute git dir
+    root: String,
+    git_dir: String,
+    branch: Option<String>,
+    rebase: Option<Rebase>,
+    detached: bool,
+    // HEAD names a branch with no commits yet
+    unborn: bool,
+    upstream: Option<String>,
+    // The upstream's name on its remote, without the `<remote>/` prefix
+    upstream_branch: Option<String>,
+    // Commits (ahead, behind) the upstream, only computed when a template asks for them
+    ahead_behind: Option<(usize, usize)>,
+    // --upstream-when-diverged: leave out an upstream that's known to be level with a
+    // clean HEAD
+    hide_synced_upstream: bool,
+    // {action} words for behind-only, ahead-only and diverged
+    action_words: [String; 3],
+    // Ahead/behind against each remote's branch of the same name, under --all-remotes
+    all_remotes: Option<Vec<RemoteDivergence>>,
+    // Abbreviated fork point of HEAD and the upstream, when asked for
+    merge_base: Option<String>,
+    // HEAD is contained in origin's default branch; None when not checked or there is
+    // no origin/HEAD
+    merged: Option<bool>,
+    // Files that differ between the merge base and the working tree, under --since-fork
+    since_fork: Option<usize>,
+    // Paths the current merge, rebase step or pick conflicted on that no longer have
+    // conflict stages; None outside such an operation
+    resolved: Option<usize>,
+    // Entries in the index, so untracked and ignored files never count
+    tracked: Option<usize>,
+    // Commits fetched for merging that HEAD doesn't contain yet
+    fetched_ahead: Option<usize>,
+    // Type of the object HEAD points at, `commit` unless moved with plumbing
+    head_kind: Option<&'static str>,
+    // History is cut off at the shallow boundary
+    shallow: bool,
+    // The ahead/behind walk crossed that boundary, so the counts may be too low
+    approximate: bool,
+    divergence_style: DivergenceStyle,
+    show_zeros: bool,
+    marker_order: String,
+    // Commits on HEAD not reachable from any remote-tracking ref
+    unpushed: Option<usize>,
+    // None when `git status` itself could not be run
+    changes: Option<ChangesSummary>,
+    last_commit: Option<String>,
+    // Number of stash entries in the chosen --stash-scope and the marker to show them
+    // with, when requested
+    stash: Option<(usize, String)>,
+    // Every stash entry regardless of scope
+    stash_total: Option<usize>,
+    stash_message: Option<String>,
+    // Configured remote names, when a template asks for them
+    remotes: Option<Vec<String>>,
+    // Abbreviated HEAD commit id, None on an unborn branch
+    hash: Option<String>,
+    // (dirty, initialized) submodule counts
+    submodules: Option<(usize, usize)>,
+    // First line printed by --status-hook
+    hook: Option<String>,
+    // G, B, N or ? for HEAD's signature, when requested
+    signature: Option<&'static str>,
+    // Seconds since FETCH_HEAD was last written, None if the repo was never fetched
+    fetch_age: Option<i64>,
+    // The last fetch is older than --stale-fetch
+    stale_fetch: bool,
+    // Operation in progress, or "detached", or empty on a plain branch
+    state: &'static str,
+}
+
+// A remote's name and HEAD's (ahead, behind) against its branch of the same name
+type RemoteDivergence = (String, (usize, usize), bool);
+
+// Tokens and conditions available to `--format` and `--format-clean`
+const TEMPLATE_VOCABULARY: Vocabulary = Vocabulary {
+    tokens: &[
+        "repo",
+        "branch",
+        "upstream",
+        "unpushed",
+        "ahead",
+        "behind",
+        "divergence",
+        "tracking",
+        "changes",
+        "conflicts",
+        "updated",
+        "deleted",
+        "untracked",
+        "last_commit",
+        "stash",
+        "hash",
+        "submodules",
+        "hook",
+        "stash_total",
+        "step",
+        "total",
+        "signature",
+        "fetch_age",
+        "state",
+        "upstream_branch",
+        "merge_base",
+        "merged",
+        "since_fork",
+        "resolved",
+        "unresolved",
+        "tracked",
+        "all_remotes",
+        "action",
+        "onto",
+        "staged",
+        "fetched_ahead",
+        "head_kind",
+        "stash_message",
+        "root",
+        "git_dir",
+        "by_dir",
+        "remotes",
+        "remote_count",
+        "in_progress_op",
+        "untracked_dirs",
+    ],
+    numeric_tokens: &[
+        "unpushed",
+        "ahead",
+        "behind",
+        "conflicts",
+        "updated",
+        "deleted",
+        "untracked",
+        "stash",
+        "since_fork",
+        "resolved",
+        "unresolved",
+        "tracked",
+        "staged",
+        "stash_total",
+        "step",
+        "total",
+        "remote_count",
+        "untracked_dirs",
+    ],
+    conditions: &[
+        "upstream",
+        "dirty",
+        "detached",
+        "ahead",
+        "behind",
+        "shallow",
+        "stale_fetch",
+        "merged",
+        "unborn",
+        "staged",
+        "unstaged",
+        "untracked",
+        "in_progress",
+    ],
+};
+
+impl GitStatus {
+    fn is_clean(&self) -> bool {
+        self.changes.as_ref().is_some_and(ChangesSummary::is_clean)
+    }
+
+    fn format(&self, options: &Options) -> String {
+        // A clean tree prefers --format-clean and a dirty one --format; either falls
+        // back to the other when only one is given
+        let template = if self.is_clean() {
+            options.format_clean.as_ref().or(options.format.as_ref())
+        } else {
+            options.format.as_ref().or(options.format_clean.as_ref())
+        };
+        if let Some(template) = template {
+            return template.render(
+                &|name| self.token(name),
+                &|name| self.condition(name),
+                options.color_depth(),
+            );
+        }
+
+        let mut components = Vec::new();
+        if let Some(branch) = &self.branch {
+            match &self.rebase {
+                Some(rebase) => components.push(format!("{}{}", branch, rebase.label())),
+                None if self.unborn => components.push(format!("{}(init)", branch)),
+                None => components.push(branch.clone()),
+            }
+        }
+        // Staged changes count as local changes here even though they don't make the
+        // tree dirty
+        let staged = self
+            .changes
+            .as_ref()
+            .is_some_and(|changes| changes.staged > 0);
+        let hide_upstream = self.hide_synced_upstream
+            && self.ahead_behind == Some((0, 0))
+            && self.is_clean()
+            && !staged;
+        if let Some(upstream) = self.upstream.as_ref().filter(|_| !hide_upstream) {
+            components.push(upstream.clone());
+        }
+        if let Some(all_remotes) = self.all_remotes_message() {
+            components.push(all_remotes);
+        }
+        if self.stale_fetch {
+            components.push(self.symbols.stale_fetch.to_string());
+        }
+        if self.merged == Some(true) {
+            components.push(self.symbols.merged.to_string());
+        }
+        if let Some(unpushed) = self.unpushed.filter(|&count| count > 0) {
+            components.push(format!("{}{}", self.symbols.unpushed, unpushed));
+        }
+        // --quiet leaves out the clean marker so a clean repo shows just its branches,
+        // unless --show-zeros asked for the counts to be spelled out anyway
+        let incomplete = self
+            .changes
+            .as_ref()
+            .is_some_and(|changes| changes.incomplete);
+        let hide_clean = options.quiet && !self.show_zeros && self.is_clean() && !incomplete;
+        let inline_stash = self.marker_order.contains('s') && !hide_clean;
+        if !hide_clean {
+            let stash = self.stash_message().filter(|_| inline_stash);
+            components.push(self.format_changes(stash.as_deref()));
+        }
+        if let Some(by_dir) = self.by_dir_message() {
+            components.push(by_dir);
+        }
+        if let Some(since_fork) = self.since_fork {
+            components.push(format!("{}{}", self.symbols.since_fork, since_fork));
+        }
+        if let Some(stash) = self.stash_message().filter(|_| !inline_stash) {
+            components.push(stash);
+        }
+        if let Some(last_commit) = &self.last_commit {
+            components.push(last_commit.clone());
+        }
+        if let Some(signature) = self.signature {
+            components.push(signature.to_string());
+        }
+        if let Some(submodules) = self.submodules_message() {
+            components.push(submodules);
+        }
+        if let Some(hook) = &self.hook {
+            components.push(hook.clone());
+        }
+
+        // Only components that are present get joined, so omitted sections never leave a
+        // dangling separator
+        components.join(options.separator.as_deref().unwrap_or(" "))
+    }
+
+    // The {changes} token leaves the stash to {stash}, wherever --marker-order puts it
+    fn changes_message(&self) -> String {
+        self.format_changes(None)
+    }
+
+    fn format_changes(&self, stash: Option<&str>) -> String {
+        match &self.changes {
+            Some(changes) => {
+                changes.format(self.symbols, self.show_zeros, &self.marker_order, stash)
+            }
+            None => "Failed to get status".to_string(),
+        }
+    }
+
+    // e.g. `pkg-a:3 pkg-b:1`
+    fn by_dir_message(&self) -> Option<String> {
+        let groups = &self.changes.as_ref()?.by_dir;
+        if groups.is_empty() {
+            return None;
+        }
+        let groups: Vec<_> = groups
+            .iter()
+            .map(|(dir, count)| format!("{}:{}", dir, count))
+            .collect();
+        Some(groups.join(" "))
+    }
+
+    fn stash_message(&self) -> Option<String> {
+        match &self.stash {
+            Some((count, symbol)) if *count > 0 => Some(format!("{}{}", symbol, count)),
+            _ => None,
+        }
+    }
+
+    // Counts that crossed a shallow boundary are lower bounds, marked with `~`
+    fn divergence_message(&self) -> String {
+        match self.ahead_behind {
+            Some((ahead, behind)) => self.format_divergence(ahead, behind, self.approximate),
+            None => String::new(),
+        }
+    }
+
+    fn format_divergence(&self, ahead: usize, behind: usize, approximate: bool) -> String {
+        let approximate = if approximate { "~" } else { "" };
+        match self.divergence_style {
+            DivergenceStyle::Arrows => {
+                let mut message = String::new();
+                if ahead > 0 {
+                    message += &format!("{}{}{}", self.symbols.ahead, approximate, ahead);
+                }
+                if behind > 0 {
+                    message += &format!("{}{}{}", self.symbols.behind, approximate, behind);
+                }
+                message
+            }
+            DivergenceStyle::Signed => {
+                format!("+{}{}/-{}{}", approximate, ahead, approximate, behind)
+            }
+        }
+    }
+
+    // `origin↑1 upstream↓5`; a remote the branch is level with shows just its name
+    fn all_remotes_message(&self) -> Option<String> {
+        let remotes = self
+            .all_remotes
+            .as_ref()
+            .filter(|remotes| !remotes.is_empty())?;
+        let message: Vec<String> = remotes
+            .iter()
+            .map(|(remote, (ahead, behind), approximate)| {
+                let divergence = self.format_divergence(*ahead, *behind, *approximate);
+                format!("{}{}", remote, divergence)
+            })
+            .collect();
+        Some(message.join(" "))
+    }
+
+    fn submodules_message(&self) -> Option<String> {
+        match self.submodules {
+            Some((dirty, total)) if total > 0 => Some(format!("sub {}/{} dirty", dirty, total)),
+            _ => None,
+        }
+    }
+
+    // {state} without the detached case: whatever `--continue` or `--abort` would act on
+    fn in_progress_op(&self) -> &'static str {
+        match self.state {
+            "detached" => "",
+            state => state,
+        }
+    }
+
+    fn condition(&self, name: &str) -> bool {
+        match name {
+            "upstream" => self.upstream.is_some(),
+            "dirty" => !self.is_clean(),
+            "detached" => self.detached,
+            "ahead" => self.ahead_behind.is_some_and(|(ahead, _)| ahead > 0),
+            "behind" => self.ahead_behind.is_some_and(|(_, behind)| behind > 0),
+            "shallow" => self.shallow,
+            "stale_fetch" => self.stale_fetch,
+            "merged" => self.merged == Some(true),
+            "unborn" => self.unborn,
+            "in_progress" => !self.in_progress_op().is_empty(),
+            "staged" => self
+                .changes
+                .as_ref()
+                .is_some_and(|changes| changes.staged > 0),
+            "unstaged" => self
+                .changes
+                .as_ref()
+                .is_some_and(|changes| changes.unstaged > 0),
+            "untracked" => self
+                .changes
+                .as_ref()
+                .is_some_and(|changes| changes.untracked > 0),
+            _ => false,
+        }
+    }
+
+    fn token(&self, name: &str) -> String {
+        let count = |count: fn(&ChangesSummary) -> usize| {
+            self.changes
+                .as_ref()
+                .map(|changes| count(changes).to_string())
+                .unwrap_or_default()
+        };
+        match name {
+            "repo" => self.repo.clone(),
+            "root" => self.root.clone(),
+            "git_dir" => self.git_dir.clone(),
+            "branch" => self.branch.clone().unwrap_or_default(),
+            "upstream" => self.upstream.clone().unwrap_or_default(),
+            // Empty without an upstream, so "0" always means in sync
+            "ahead" => self
+                .ahead_behind
+                .map(|(ahead, _)| ahead.to_string())
+                .unwrap_or_default(),
+            "behind" => self
+                .ahead_behind
+                .map(|(_, behind)| behind.to_string())
+                .unwrap_or_default(),
+            "divergence" => self.divergence_message(),
+            // The whole tracking picture in one token: divergence, `[gone]`, or nothing
+            "tracking" => {
+                if self.upstream.as_deref() == Some(GONE_UPSTREAM) {
+                    GONE_UPSTREAM.to_string()
+                } else {
+                    self.divergence_message()
+                }
+            }
+            "unpushed" => self
+                .unpushed
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+            "changes" => self.changes_message(),
+            "conflicts" => count(|changes| changes.conflicts),
+            "updated" => count(|changes| changes.updated),
+            "deleted" => count(|changes| changes.deleted),
+            "untracked" => count(|changes| changes.untracked),
+            "untracked_dirs" => count(|changes| changes.untracked_dirs),
+            "staged" => count(|changes| changes.staged),
+            "by_dir" => self.by_dir_message().unwrap_or_default(),
+            "remotes" => self.remotes.as_deref().unwrap_or_default().join(","),
+            "remote_count" => self
+                .remotes
+                .as_ref()
+                .map(|remotes| remotes.len().to_string())
+                .unwrap_or_default(),
+            "last_commit" => self.last_commit.clone().unwrap_or_default(),
+            "stash" => self
+                .stash
+                .as_ref()
+                .map(|(count, _)| count.to_string())
+                .unwrap_or_default(),
+            "stash_message" => self.stash_message.clone().unwrap_or_default(),
+            "stash_total" => self
+                .stash_total
+                .map(|total| total.to_string())
+                .unwrap_or_default(),
+            "hash" => self.hash.clone().unwrap_or_default(),
+            "submodules" => self.submodules_message().unwrap_or_default(),
+            "hook" => self.hook.clone().unwrap_or_default(),
+            // Progress through a rebase, `git am` or a cherry-pick or revert sequence,
+            // empty otherwise
+            "step" => self
+                .rebase
+                .as_ref()
+                .and_then(|rebase| rebase.step)
+                .map(|step| step.to_string())
+                .unwrap_or_default(),
+            "total" => self
+                .rebase
+                .as_ref()
+                .and_then(|rebase| rebase.total)
+                .map(|total| total.to_string())
+                .unwrap_or_default(),
+            "signature" => self.signature.unwrap_or_default().to_string(),
+            "fetch_age" => self.fetch_age.map(format_age).unwrap_or_default(),
+            "state" => self.state.to_string(),
+            "in_progress_op" => self.in_progress_op().to_string(),
+            "upstream_branch" => self.upstream_branch.clone().unwrap_or_default(),
+            "merge_base" => self.merge_base.clone().unwrap_or_default(),
+            "onto" => self
+                .rebase
+                .as_ref()
+                .and_then(|rebase| rebase.onto.clone())
+                .unwrap_or_default(),
+            "all_remotes" => self.all_remotes_message().unwrap_or_default(),
+            "action" => {
+                let [pull, push, diverged] = &self.action_words;
+                match self.ahead_behind {
+                    Some((0, behind)) if behind > 0 => pull.clone(),
+                    Some((ahead, 0)) if ahead > 0 => push.clone(),
+                    Some((ahead, behind)) if ahead > 0 && behind > 0 => diverged.clone(),
+                    _ => String::new(),
+                }
+            }
+            "since_fork" => self
+                .since_fork
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+            "resolved" => self
+                .resolved
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+            "unresolved" if self.resolved.is_some() => count(|changes| changes.conflicts),
+            "tracked" => self
+                .tracked
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+            "head_kind" => self.head_kind.unwrap_or_default().to_string(),
+            "fetched_ahead" => match self.fetched_ahead {
+                Some(count) if count > 0 => format!("{}{}", self.symbols.fetched, count),
+                _ => String::new(),
+            },
+            "merged" if self.merged == Some(true) => self.symbols.merged.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn main() {
+    let mut timings = Timings::new();
+    let mut options = parse_args();
+
+    // Honor GIT_DIR, GIT_COMMON_DIR, GIT_WORK_TREE and friends the way the git
+    // subprocesses will, searching upwards from the current directory otherwise
+    let repo = match Repository::open_from_env() {
+        Ok(repo) => repo,
+        Err(err) if json_errors() => fail(error_kind(&err), err.message()),
+        // Plain mode exits silently, as it always has, so a prompt outside a repository
+        // (or in a broken one) just shows nothing
+        Err(_) => process::exit(1),
+    };
+    // libgit2 applies GIT_NAMESPACE to every ref lookup, HEAD included, so HEAD would
+    // read as refs/namespaces/<ns>/refs/heads/... and the branch as unborn. Git only
+    // namespaces refs for transport, and git status and git branch show the worktree's
+    // own HEAD, so do the same
+    if env::var_os("GIT_NAMESPACE").is_some() {
+        let _ = repo.remove_namespace();
+    }
+    // Inside the git dir libgit2 still finds the repository but git status refuses to
+    // run, so report on the enclosing work tree as if run from its root
+    if let Some(workdir) = work_tree(&repo).filter(|_| is_inside_git_dir(&repo)) {
+        let _ = env::set_current_dir(workdir);
+    }
+    if options.dotfiles {
+        if let Some(git_dir) = git_dir_in_work_tree(&repo) {
+            options.excludes.push(git_dir);
+        }
+    }
+    timings.record("discover");
+    let git_dir_before = options.assert_readonly.then(|| snapshot_git_dir(&repo));
+
+    // Mid-rebase HEAD is detached, so show the branch being rebased instead
+    let rebase = get_rebase(&repo);
+    let resolve = || BranchInfo {
+        branch: match rebase.as_ref().and_then(|rebase| rebase.branch.clone()) {
+            Some(branch) => Some(branch),
+            None => get_current_branch_name(&repo).ok(),
+        },
+        upstream: get_upstream_branch_name(&repo).ok(),
+        upstream_branch: get_upstream_merge_branch(&repo),
+    };
+    let BranchInfo {
+        branch,
+        upstream,
+        upstream_branch,
+    } = if options.branch_cache {
+        // Writing the cache would trip --assert-readonly, so only read it then
+        cached_branch_info(&repo, !options.assert_readonly, resolve)
+    } else {
+        resolve()
+    };
+    timings.record("branch");
+
+    // Without tracking configured, optionally fall back to the remote's default branch,
+    // marked with a leading ellipsis so it isn't mistaken for a real upstream
+    let upstream = upstream.or_else(|| {
+        if options.compare_default {
+            get_default_branch_name(&repo)
+                .ok()
+                .map(|name| format!("{}{}", options.symbols().default_upstream, name))
+        } else {
+            None
+        }
+    });
+    timings.record("upstream");
+
+    let unpushed = if options.unpushed {
+        count_unpushed_commits(&repo).ok()
+    } else {
+        None
+    };
+
+    let changes = get_git_status(&repo, &options);
+    if let Some(changes) = &changes {
+        timings.entries = changes.entries;
+        timings.fast_clean = changes.fast_clean;
+    }
+    timings.record("status");
+
+    if options.warn_skip_worktree {
+        for path in get_staged_skip_worktree_paths() {
+            eprintln!(
+                "gitstatus: warning: {} is marked skip-worktree but has staged changes",
+                path
+            );
+        }
+    }
+
+    // Abbreviated hash and age of HEAD, omitted on an unborn branch
+    let last_commit = if options.last_commit {
+        get_last_commit(&repo).ok()
+    } else {
+        None
+    };
+
+    let (stash, stash_total) = match &options.stash_symbol {
+        Some(symbol) => {
+            let (on_branch, total) = count_stashes(&repo, branch.as_deref());
+            let count = if options.stash_current_branch {
+                on_branch
+            } else {
+                total
+            };
+            (Some((count, symbol.clone())), Some(total))
+        }
+        None => (None, None),
+    };
+
+    let stash_message = if options.template_uses("stash_message") {
+        get_stash_message(&repo, &options, branch.as_deref())
+    } else {
+        None
+    };
+    let remotes = if options.template_uses("remotes") || options.template_uses("remote_count") {
+        repo.remotes().ok().map(|remotes| {
+            remotes
+                .iter()
+                .flatten()
+                .map(|remote| remote.to_string())
+                .collect()
+        })
+    } else {
+        None
+    };
+
+    let hash = get_head_hash(&repo).ok();
+
+    let signature = if options.verify_signature || options.template_uses("signature") {
+        Some(get_signature_status(&repo))
+    } else {
+        None
+    };
+
+    let fetch_age = get_fetch_age(&repo);
+    let stale_fetch = options
+        .stale_fetch
+        .zip(fetch_age)
+        .is_some_and(|(limit, age)| age > limit);
+
+    let ahead_behind = if options.upstream_when_diverged
+        || options.exit_code_divergence
+        || ["ahead", "behind", "divergence", "tracking", "action"]
+            .iter()
+            .any(|name| options.template_uses(name))
+    {
+        get_ahead_behind(&repo, &options).ok()
+    } else {
+        None
+    };
+    let all_remotes = if options.all_remotes || options.template_uses("all_remotes") {
+        get_all_remotes_ahead_behind(&repo).ok()
+    } else {
+        None
+    };
+    let merge_base = if options.template_uses("merge_base") {
+        get_merge_base(&repo, &options).ok()
+    } else {
+        None
+    };
+    let since_fork = if options.since_fork || options.template_uses("since_fork") {
+        count_changes_since_fork(&repo, &options).ok()
+    } else {
+        None
+    };
+    let resolved = if ["resolved", "unresolved"]
+        .iter()
+        .any(|name| options.template_uses(name))
+    {
+        count_resolved_conflicts(&repo)
+    } else {
+        None
+    };
+    let tracked = if options.template_uses("tracked") {
+        repo.index().map(|index| index.len()).ok()
+    } else {
+        None
+    };
+    let fetched_ahead = if options.template_uses("fetched_ahead") {
+        count_unmerged_fetched(&repo).ok()
+    } else {
+        None
+    };
+    let merged = if options.merged_check || options.template_uses("merged") {
+        is_merged_into_default(&repo).ok()
+    } else {
+        None
+    };
+
+    let submodules = if options.submodules {
+        get_submodule_counts(&repo).ok()
+    } else {
+        None
+    };
+
+    let hook = options
+        .status_hook
+        .as_ref()
+        .and_then(|hook| run_status_hook(&repo, hook));
+
+    let detached = repo.head_detached().unwrap_or(false);
+    let state = get_state(&repo, detached);
+
+    // --strip-prefix trims e.g. a `user/` convention from the displayed name only, so
+    // it comes after every lookup that needs the real one, like matching stashes
+    let branch = branch.map(|branch| match &options.strip_prefix {
+        Some(prefix) => branch
+            .strip_prefix(prefix.as_str())
+            .filter(|rest| !rest.is_empty())
+            .unwrap_or(&branch)
+            .to_string(),
+        None => branch,
+    });
+
+    let status = GitStatus {
+        symbols: options.symbols(),
+        repo: get_repo_name(&repo),
+        root: work_tree(&repo)
+            .map(|root| absolute_path(&root))
+            .unwrap_or_default(),
+        git_dir: absolute_path(repo.path()),
+        branch,
+        rebase,
+        detached,
+        unborn: repo
+            .head()
+            .is_err_and(|err| err.code() == ErrorCode::UnbornBranch),
+        upstream,
+        upstream_branch,
+        ahead_behind,
+        hide_synced_upstream: options.upstream_when_diverged,
+        action_words: options.action_words.clone().unwrap_or_else(|| {
+            [
+                "pull".to_string(),
+                "push".to_string(),
+                "diverged".to_string(),
+            ]
+        }),
+        all_remotes,
+        merge_base,
+        merged,
+        since_fork,
+        resolved,
+        tracked,
+        fetched_ahead,
+        head_kind: repo
+            .head()
+            .ok()
+            .and_then(|head| head.target())
+            .and_then(|id| repo.find_object(id, None).ok())
+            .and_then(|object| object.kind())
+            .map(|kind| kind.str()),
+        shallow: repo.is_shallow(),
+        approximate: ahead_behind.is_some()
+            && get_comparison_commits(&repo, &options)
+                .map(|(local, upstream)| crosses_shallow_boundary(&repo, local, upstream))
+                .unwrap_or(false),
+        divergence_style: options.divergence,
+        show_zeros: options.show_zeros,
+        marker_order: options
+            .marker_order
+            .clone()
+            .unwrap_or_else(|| MARKER_ORDER.to_string()),
+        unpushed,
+        changes,
+        last_commit,
+        stash,
+        stash_total,
+        stash_message,
+        remotes,
+        hash,
+        submodules,
+        hook,
+        signature,
+        fetch_age,
+        stale_fetch,
+        state,
+    };
+    if let Some(before) = git_dir_before {
+        if snapshot_git_dir(&repo) != before {
+            fail(
+                "readonly",
+                &format!(
+                    "{} was modified while gathering status",
+                    repo.path().display()
+                ),
+            );
+        }
+    }
+
+    let output = status.format(&options);
+
+    // Wrap last, and never wrap nothing, so prompts don't end up with a stray "()"
+    let output = if output.is_empty() {
+        output
+    } else {
+        format!("{}{}{}", options.prefix, output, options.suffix)
+    };
+    // Counted after colors are applied, since those take no columns
+    let output = match options.max_width {
+        Some(max_width) => width::truncate(&output, max_width, options.symbols().ellipsis),
+        None => output,
+    };
+    if options.print_width {
+        eprintln!("{}", width::display_width(&output));
+    }
+    let mut printed = output + "\n";
+
+    // Recently checked out branches go on their own line so the status line stays
+    // parseable
+    if let Some(count) = options.recent {
+        let recent = get_recent_branches(&repo, count);
+        if !recent.is_empty() {
+            printed += &(recent.join(" ") + "\n");
+        }
+    }
+    timings.record("extras");
+
+    match &options.output_file {
+        Some(path) => {
+            if let Err(err) = write_atomically(path.as_ref(), &printed) {
+                fail("io", &format!("failed to write {}: {}", path, err));
+            }
+        }
+        None => print!("{}", printed),
+    }
+
+    if options.print_timings_json {
+        eprintln!("{}", timings.to_json());
+    }
+    if let Some(path) = &options.timings_file {
+        if let Err(err) = fs::write(path, timings.to_json() + "\n") {
+            eprintln!("gitstatus: failed to write {}: {}", path, err);
+        }
+    }
+
+    if options.exit_code_divergence {
+        process::exit(divergence_exit_code(status.ahead_behind));
+    }
+}
+
+// --exit-code-divergence: 0 in sync, 10 ahead only, 11 behind only, 12 diverged and
+// 13 when there is no upstream to compare with. Errors keep using 1
+fn divergence_exit_code(ahead_behind: Option<(usize, usize)>) -> i32 {
+    match ahead_behind {
+        Some((0, 0)) => 0,
+        Some((_, 0)) => 10,
+        Some((0, _)) => 11,
+        Some(_) => 12,
+        None => 13,
+    }
+}
+
+const BRANCH_CACHE_FILE: &str = "gitstatus-branch-cache";
+
+// Branch and upstream resolution reads HEAD, packed-refs and config, plus the loose
+// remote-tracking ref whose absence makes the upstream `[gone]`, so their sizes and
+// mtimes make the cache key
+fn branch_cache_key(repo: &Repository) -> String {
+    let common_dir = common_dir(repo);
+    let mut files = vec![
+        repo.path().join("HEAD"),
+        common_dir.join("packed-refs"),
+        common_dir.join("config"),
+    ];
+    // Resolved from config alone, so it's known even once a prune deleted the ref
+    let upstream_ref = repo.head().ok().and_then(|head| {
+        let name = head.name()?.to_string();
+        let upstream = repo.branch_upstream_name(&name).ok()?;
+        upstream.as_str().map(str::to_string)
+    });
+    if let Some(upstream_ref) = upstream_ref {
+        files.push(common_dir.join(upstream_ref));
+    }
+    let stamps: Vec<_> = files
+        .iter()
+        .map(|file| match fs::metadata(file) {
+            Ok(metadata) => {
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_nanos());
+                format!("{}:{}", metadata.len(), mtime)
+            }
+            Err(_) => "-".to_string(),
+        })
+        .collect();
+    stamps.join(" ")
+}
+
+// Where refs and config shared between linked worktrees live. git2 doesn't expose it,
+// so follow the same `commondir` file libgit2 reads
+fn common_dir(repo: &Repository) -> std::path::PathBuf {
+    if let Some(dir) = env::var_os("GIT_COMMON_DIR") {
+        return dir.into();
+    }
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(contents) => repo.path().join(contents.trim_end()),
+        Err(_) => repo.path().to_path_buf(),
+    }
+}
+
+// The cache is the key line followed by the branch, upstream and upstream merge ref,
+// one per line and empty when unset; none of them can contain a newline
+fn cached_branch_info(
+    repo: &Repository,
+    write: bool,
+    resolve: impl FnOnce() -> BranchInfo,
+) -> BranchInfo {
+    let path = repo.path().join(BRANCH_CACHE_FILE);
+    // Taken before resolving, so a HEAD moved meanwhile misses the cache next time
+    let key = branch_cache_key(repo);
+
+    let cached = fs::read_to_string(&path).ok().and_then(|contents| {
+        let mut lines = contents.lines();
+        if lines.next()? != key {
+            return None;
+        }
+        let mut field = || {
+            lines
+                .next()
+                .map(|line| Some(line.to_string()).filter(|line| !line.is_empty()))
+        };
+        Some(BranchInfo {
+            branch: field()?,
+            upstream: field()?,
+            upstream_branch: field()?,
+        })
+    });
+    if let Some(info) = cached {
+        return info;
+    }
+
+    let info = resolve();
+    if write {
+        let contents = format!(
+            "{}\n{}\n{}\n{}\n",
+            key,
+            info.branch.as_deref().unwrap_or_default(),
+            info.upstream.as_deref().unwrap_or_default(),
+            info.upstream_branch.as_deref().unwrap_or_default()
+        );
+        // A prompt can always fall back to resolving again, so failures stay quiet
+        let _ = write_atomically(&path, &contents);
+    }
+    info
+}
+
+// Status bars poll the file, so they must never catch it half written: write a sibling
+// temp file and rename it over the target, which replaces it in one step
+fn write_atomically(target: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(target.file_name().unwrap_or(target.as_os_str()));
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp = target.with_file_name(temp_name);
+
+    fs::write(&temp, contents)
+        .and_then(|()| fs::rename(&temp, target))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+}
+
+// Printed by --help; the template tokens and conditions are appended from
+// TEMPLATE_VOCABULARY so the list can't fall behind
+const USAGE: &str = "\
+usage: gitstatus [options]
+
+Prints a one-line summary of the repository containing the current directory:
+branch, upstream and change markers (! conflicts, + modified, x deleted,
+n untracked, or a clean mark).
+
+Layout
+  --format <template>           template for a dirty tree, see Templates
+  --format-clean <template>     template for a clean tree; when only one of the
+                                two is given it is used for both
+  --prefix <text>               text before the output
+  --suffix <text>               text after the output
+  --separator <text>            between components of the default layout
+  --quiet                       leave out the clean mark in a clean tree
+  --show-zeros                  show every change marker, zero or not
+  --marker-order <letters>      order of the c, m, d and u markers, e.g. umdc;
+                                an s moves the stash count in among them
+  --ascii                       ASCII symbols: OK for the clean mark, M for
+                                modified, D deleted, U untracked, ^ unpushed,
+                                > ahead, < behind, ... default upstream and
+                                truncation, * stale fetch, m merged, d since
+                                fork and v fetched
+  --divergence arrows|signed    ahead/behind as arrows (default) or +3/-2
+  --upstream-when-diverged      hide an upstream that is in sync and a tree with
+                                nothing staged, modified or untracked
+  --strip-prefix <prefix>       drop a prefix such as user/ from the branch name
+  --max-width <columns>         truncate the output to this display width
+  --print-width                 print the output's display width after it
+  --color auto|always|never     whether {color:...} tokens emit escape codes;
+                                auto means only when stdout is a terminal
+
+Counts
+  -u, --untracked-files no|normal|all
+                                how untracked files are listed; defaults to
+                                status.showUntrackedFiles
+  --pathspec <path>             only count changes under this path prefix,
+                                relative to the current directory; repeatable
+  --exclude <glob>              leave paths matching this glob, relative to the
+                                repository root, out of the counts (git itself
+                                is unaffected); repeatable
+  --rename-threshold <percent>  similarity for git status's rename detection
+  --include-assume-unchanged    also count modified assume-unchanged and
+                                skip-worktree files
+  --warn-skip-worktree          warn about skip-worktree files with staged
+                                changes
+  --by-dir <depth>              count changes per leading directories
+  --submodules                  show how many submodules are dirty
+  --base <rev>                  summarize the difference from this revision to
+                                --target instead of the work tree
+  --target <rev>                the other end for --base, HEAD by default
+  --fast-clean                  skip git status when cheap checks say the tree
+                                is clean: nothing in progress, the index matches
+                                HEAD, a sample of tracked files kept the size
+                                and mtime the index recorded, and nothing at the
+                                top of the work tree is newer than the index.
+                                It can report clean wrongly when the only edits
+                                keep a file's size outside the sample, or when
+                                new untracked files sit below the top level
+  --dotfiles                    for a bare repository managing $HOME; implies
+                                -u no unless -u is given
+
+Branches and upstreams
+  --compare-default             compare with origin's default branch when the
+                                branch tracks no upstream
+  --all-remotes                 ahead/behind against each remote's branch of the
+                                same name
+  --unpushed                    commits on HEAD that no remote has yet
+  --merged-check                mark a branch already merged into origin's
+                                default branch
+  --since-fork                  files changed since the fork from the upstream
+  --stale-fetch <duration>      mark an upstream not fetched for this long, in
+                                seconds or with an s, m, h, d or w suffix
+  --action-words <pull,push,diverged>
+                                what {action} says when behind, ahead or both
+  --recent <count>              list recently checked out branches first
+  --branch-cache                reuse the branch and upstream while refs and
+                                config are unchanged
+
+Extras
+  --stash                       show the stash count as $N when nonzero
+  --stash-symbol <text>         show the stash count with this marker instead
+  --stash-scope current|all     count only stashes made on the current branch,
+                                or all of them (default)
+  --stash-message-length <n>    truncate {stash_message} to n columns
+  --last-commit                 HEAD's short hash and age
+  --verify-signature            HEAD's signature status
+  --status-hook <command>       append the output of a command run in the work
+                                tree
+
+Output and errors
+  --output-file <path>          write the result to this file atomically
+  --print-timings-json          print per-phase timings as JSON on stderr
+  --timings-file <path>         write the same timings to a file
+  --error-format plain|json     report failures as plain text or a JSON object
+                                with error and kind; plain mode prints nothing
+                                when no repository can be opened
+  --strict                      fail when git status reports an error
+  --assert-readonly             fail if the git dir was written to
+  --exit-code-divergence        exit with the upstream divergence, see below
+  -h, --help                    print this help
+
+Exit status
+  0 on success and 1 on an error, including a directory outside any repository.
+  --exit-code-divergence replaces the success code with 0 in sync, 10 ahead,
+  11 behind, 12 diverged or 13 no upstream.
+
+Templates
+  {token} inserts a value and {{ and }} are literal braces. Numeric tokens
+  take a width, {ahead:3}, and are right-aligned to it. %(condition?body)
+  renders body only when the condition holds. {color:<name>} is one of black,
+  red, green, yellow, blue, magenta, cyan, white, bold, dim and reset, and
+  {color:196} or {color:#ff8800} pick a 256-color index or an RGB value.
+  {tracking} shows the divergence, or [gone] when the upstream branch was
+  deleted. {tracked} counts index entries, so untracked and ignored files are
+  never included. {action} is empty when in sync or without an upstream.
+  Divergence counts marked ~ may be too low because they reach the cut of a
+  shallow clone.
+";
+
+// The usage text plus every template token and condition, wrapped to 80 columns
+fn help_text() -> String {
+    let mut help = USAGE.to_string();
+    let lists = [
+        ("Tokens:", TEMPLATE_VOCABULARY.tokens),
+        ("Conditions:", TEMPLATE_VOCABULARY.conditions),
+    ];
+    for (heading, names) in lists {
+        let mut line = format!("\n  {}", heading);
+        for name in names {
+            if line.len() + name.len() + 1 > 80 {
+                help += &line;
+                line = "\n   ".to_string();
+            }
+            line += " ";
+            line += name;
+        }
+        help += &line;
+    }
+    help + "\n"
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(value) => value,
+            None => usage_error(&format!("{} requires a value", flag)),
+        };
+
+        match flag.as_str() {
+            "--recent" => options.recent = Some(parse_count(&flag, &value())),
+            "--include-assume-unchanged" => options.include_assume_unchanged = true,
+            "--last-commit" => options.last_commit = true,
+            "--verify-signature" => options.verify_signature = true,
+            "--assert-readonly" => options.assert_readonly = true,
+            "--merged-check" => options.merged_check = true,
+            "--since-fork" => options.since_fork = true,
+            "--by-dir" => match parse_count(&flag, &value()) {
+                0 => usage_error(&format!("{} expects a depth of at least 1", flag)),
+                depth => options.by_dir = Some(depth),
+            },
+            "--all-remotes" => options.all_remotes = true,
+            "--upstream-when-diverged" => options.upstream_when_diverged = true,
+            "--strip-prefix" => options.strip_prefix = Some(value()),
+            "--dotfiles" => options.dotfiles = true,
+            "--fast-clean" => options.fast_clean = true,
+            "--exit-code-divergence" => options.exit_code_divergence = true,
+            "--branch-cache" => options.branch_cache = true,
+            "--rename-threshold" => {
+                let percent = value();
+                let threshold = parse_count(&flag, percent.trim_end_matches('%'));
+                if threshold > 100 {
+                    usage_error(&format!("{} expects a percentage, got {}", flag, percent));
+                }
+                options.rename_threshold = Some(threshold);
+            }
+            "--base" => options.base = Some(value()),
+            "--target" => options.target = Some(value()),
+            "--action-words" => {
+                let words = value();
+                match words.split(',').collect::<Vec<_>>()[..] {
+                    [pull, push, diverged] => {
+                        options.action_words =
+                            Some([pull.to_string(), push.to_string(), diverged.to_string()])
+                    }
+                    _ => usage_error(&format!(
+                        "{} expects three comma-separated words, got {}",
+                        flag, words
+                    )),
+                }
+            }
+            "--stale-fetch" => options.stale_fetch = Some(parse_duration(&flag, &value())),
+            "--prefix" => options.prefix = value(),
+            "--suffix" => options.suffix = value(),
+            "--print-timings-json" => options.print_timings_json = true,
+            "--timings-file" => options.timings_file = Some(value()),
+            "--output-file" => options.output_file = Some(value()),
+            "--format" => options.format = Some(parse_template(&flag, &value())),
+            "--submodules" => options.submodules = true,
+            "--compare-default" => options.compare_default = true,
+            "--strict" => options.strict = true,
+            "--unpushed" => options.unpushed = true,
+            "--ascii" => options.ascii = true,
+            "--stash" => {
+                options.stash_symbol.get_or_insert_with(|| "$".to_string());
+            }
+            "--stash-symbol" => options.stash_symbol = Some(value()),
+            "--stash-message-length" => {
+                options.stash_message_length = Some(parse_count(&flag, &value()))
+            }
+            "--stash-scope" => match value().as_str() {
+                "all" => options.stash_current_branch = false,
+                "current" => options.stash_current_branch = true,
+                other => usage_error(&format!("{} expects current or all, got {}", flag, other)),
+            },
+            "--warn-skip-worktree" => options.warn_skip_worktree = true,
+            "--quiet" => options.quiet = true,
+            "--status-hook" => options.status_hook = Some(value()),
+            "--print-width" => options.print_width = true,
+            "--max-width" => options.max_width = Some(parse_count(&flag, &value())),
+            "--show-zeros" => options.show_zeros = true,
+            "--marker-order" => {
+                let order = value();
+                let mut letters: Vec<char> = order.chars().filter(|&c| c != 's').collect();
+                letters.sort_unstable();
+                let mut known: Vec<char> = MARKER_ORDER.chars().collect();
+                known.sort_unstable();
+                if letters != known || order.matches('s').count() > 1 {
+                    usage_error(&format!(
+                        "{} expects each of {} exactly once and optionally s, got {}",
+                        flag, MARKER_ORDER, order
+                    ));
+                }
+                options.marker_order = Some(order);
+            }
+            "--separator" => options.separator = Some(value()),
+            "--divergence" => match value().as_str() {
+                "arrows" => options.divergence = DivergenceStyle::Arrows,
+                "signed" => options.divergence = DivergenceStyle::Signed,
+                other => usage_error(&format!("{} expects arrows or signed, got {}", flag, other)),
+            },
+            "--pathspec" => options.pathspecs.push(value()),
+            "--exclude" => options.excludes.push(value()),
+            "--color" => match value().as_str() {
+                "auto" => options.color = ColorMode::Auto,
+                "always" => options.color = ColorMode::Always,
+                "never" => options.color = ColorMode::Never,
+                other => usage_error(&format!(
+                    "{} expects auto, always or never, got {}",
+                    flag, other
+                )),
+            },
+            // Already taken into account by json_errors()
+            "--error-format" => match value().as_str() {
+                "plain" | "json" => {}
+                other => usage_error(&format!("{} expects plain or json, got {}", flag, other)),
+            },
+            "--format-clean" => options.format_clean = Some(parse_template(&flag, &value())),
+            "-u" | "--untracked-files" => {
+                let mode = value();
+                if !matches!(mode.as_str(), "no" | "normal" | "all") {
+                    usage_error(&format!("{} expects no, normal or all, got {}", flag, mode));
+                }
+                options.untracked_files = Some(mode);
+            }
+            "-h" | "--help" => {
+                print!("{}", help_text());
+                process::exit(0);
+            }
+            _ => usage_error(&format!("unknown option {}", arg)),
+        }
+    }
+
+    if options.target.is_some() && options.base.is_none() {
+        usage_error("--target requires --base");
+    }
+    // A work tree that is all of $HOME is too big to scan for untracked files, whatever
+    // status.showUntrackedFiles says; an explicit -u still wins
+    if options.dotfiles {
+        options
+            .untracked_files
+            .get_or_insert_with(|| "no".to_string());
+    }
+
+    options
+}
+
+fn parse_count(flag: &str, value: &str) -> usize {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("{} expects a number, got {}", flag, value)))
+}
+
+// Seconds, or a number with an s/m/h/d/w suffix
+fn parse_duration(flag: &str, value: &str) -> i64 {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => usage_error(&format!(
+            "{} expects a duration like 3d, got {}",
+            flag, value
+        )),
+    };
+    match number.parse::<i64>() {
+        Ok(number) => number.saturating_mul(multiplier),
+        Err(_) => usage_error(&format!(
+            "{} expects a duration like 3d, got {}",
+            flag, value
+        )),
+    }
+}
+
+fn parse_template(flag: &str, value: &str) -> Template {
+    Template::parse(value, &TEMPLATE_VOCABULARY)
+        .unwrap_or_else(|err| usage_error(&format!("invalid {}: {}", flag, err)))
+}
+
+fn usage_error(message: &str) -> ! {
+    fail("usage", message)
+}
+
+// Failures exit through here, so --error-format json covers all of them: a usage error,
+// a repository that can't be opened (kinds from error_kind), an --assert-readonly
+// violation, a failed write or a git status --strict won't accept
+fn fail(kind: &str, message: &str) -> ! {
+    if json_errors() {
+        eprintln!(
+            "{{\"error\":{},\"kind\":\"{}\"}}",
+            json_string(message),
+            kind
+        );
+    } else {
+        eprintln!("gitstatus: {}", message);
+    }
+    process::exit(1);
+}
+
+// Scanned from the raw arguments rather than Options, so an error in an argument
+// before --error-format is still reported as JSON
+fn json_errors() -> bool {
+    let mut json = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.split_once('=') {
+            Some(("--error-format", value)) => json = value == "json",
+            _ if arg == "--error-format" => json = args.next().as_deref() == Some("json"),
+            _ => {}
+        }
+    }
+    json
+}
+
+// Every git we run skips optional locks, so `git status` doesn't take index.lock to
+// write back refreshed stat data and gitstatus never writes to the git dir
+fn git_command() -> Command {
+    let mut command = Command::new("git");
+    command.env("GIT_OPTIONAL_LOCKS", "0");
+    command
+}
+
+// libgit2 gives a bare repository no workdir even when GIT_WORK_TREE supplies one, as
+// in the bare-repo-plus-$HOME dotfiles layout, while git itself uses it
+fn work_tree(repo: &Repository) -> Option<std::path::PathBuf> {
+    match repo.workdir() {
+        Some(workdir) => Some(workdir.to_path_buf()),
+        None => env::var_os("GIT_WORK_TREE").and_then(|dir| std::path::absolute(dir).ok()),
+    }
+}
+
+// In the dotfiles layout the git dir (say ~/.dotfiles) sits inside the work tree, and
+// since it isn't called .git, git status would list everything in it as untracked
+fn git_dir_in_work_tree(repo: &Repository) -> Option<String> {
+    let work_tree = fs::canonicalize(work_tree(repo)?).ok()?;
+    let git_dir = fs::canonicalize(repo.path()).ok()?;
+    let relative = git_dir.strip_prefix(&work_tree).ok()?;
+    if relative.as_os_str().is_empty() || relative == std::path::Path::new(".git") {
+        return None;
+    }
+    Some(relative.to_string_lossy().to_string())
+}
+
+fn is_inside_git_dir(repo: &Repository) -> bool {
+    let cwd = env::current_dir().and_then(fs::canonicalize);
+    let git_dir = fs::canonicalize(repo.path());
+    match (cwd, git_dir) {
+        (Ok(cwd), Ok(git_dir)) => cwd.starts_with(git_dir),
+        _ => false,
+    }
+}
+
+// Names, sizes and mtimes of the top level of the git dir, which is where an index
+// rewrite, a lock file or a new FETCH_HEAD would show up
+fn snapshot_git_dir(repo: &Repository) -> Vec<(String, u64, Option<SystemTime>)> {
+    let mut entries: Vec<_> = fs::read_dir(repo.path())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| {
+            let metadata = entry.metadata().ok();
+            (
+                entry.file_name().to_string_lossy().to_string(),
+                metadata.as_ref().map_or(0, |metadata| metadata.len()),
+                metadata.and_then(|metadata| metadata.modified().ok()),
+            )
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+// Coarse categories for --error-format json, so tooling can branch without parsing
+// messages
+fn error_kind(err: &Error) -> &'static str {
+    match (err.class(), err.code()) {
+        (_, ErrorCode::NotFound) => "not_a_repo",
+        (ErrorClass::Os, _) => "io",
+        (ErrorClass::Config, _) => "config",
+        _ => "git",
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if c.is_control() => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Directory name of the worktree root, or of the git dir itself for a bare repository;
+// empty when the root has no name (e.g. `/`)
+fn get_repo_name(repo: &Repository) -> String {
+    let root = work_tree(repo).unwrap_or_else(|| repo.path().to_path_buf());
+    root.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// libgit2 keeps a trailing slash on both the workdir and the git dir, which would
+// double up when tooling joins a path onto either
+fn absolute_path(path: &std::path::Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let path = path.to_string_lossy();
+    match path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+// Before the first commit HEAD already names a branch, it just doesn't exist yet
+fn get_current_branch_name(repo: &Repository) -> Result<String, Error> {
+    let head = match repo.head() {
+        Ok(head) => head,
+        Err(err) if err.code() == ErrorCode::UnbornBranch => {
+            let head = repo.find_reference("HEAD")?;
+            let target = head.symbolic_target().unwrap_or_default();
+            let branch = target.strip_prefix("refs/heads/").unwrap_or(target);
+            return Ok(branch.to_string());
+        }
+        Err(err) => return Err(err),
+    };
+    if head.is_branch() {
+        let shorthand = head.shorthand().unwrap_or("unknown branch");
+        Ok(shorthand.to_string())
+    } else {
+        Ok("HEAD is detached".to_string())
+    }
+}
+
+// rebase-merge is used by interactive and merge-backend rebases, rebase-apply by the
+// apply backend and by `git am`, which leaves an `applying` marker; they name their
+// progress files differently
+fn get_rebase(repo: &Repository) -> Option<Rebase> {
+    let apply = repo.path().join("rebase-apply");
+    let (dir, step_file, total_file) = if repo.path().join("rebase-merge").is_dir() {
+        (repo.path().join("rebase-merge"), "msgnum", "end")
+    } else if apply.is_dir() {
+        (apply, "next", "last")
+    } else {
+        return get_sequence(repo);
+    };
+    let operation = if dir.join("applying").exists() {
+        "AM"
+    } else {
+        "REBASE"
+    };
+    let read = |name: &str| {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|contents| contents.trim().to_string())
+    };
+
+    Some(Rebase {
+        operation,
+        branch: read("head-name").and_then(|name| {
+            name.strip_prefix("refs/heads/")
+                .map(|branch| branch.to_string())
+        }),
+        step: read(step_file).and_then(|step| step.parse().ok()),
+        total: read(total_file).and_then(|total| total.parse().ok()),
+        onto: read("onto").and_then(|onto| name_commit(repo, &onto)),
+    })
+}
+
+// `git cherry-pick A..B` and `git revert A..B` keep only what's left to do in
+// sequencer/todo, its first line being the pick that stopped, so what's done is counted
+// as the commits made since the sequence started from sequencer/head
+fn get_sequence(repo: &Repository) -> Option<Rebase> {
+    let dir = repo.path().join("sequencer");
+    let todo = fs::read_to_string(dir.join("todo")).ok()?;
+    let todo: Vec<_> = todo
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let operation = match todo.first()?.split(' ').next() {
+        Some("revert") => "REVERT",
+        _ => "CHERRY-PICK",
+    };
+
+    let start = Oid::from_str(fs::read_to_string(dir.join("head")).ok()?.trim()).ok()?;
+    let mut revwalk = repo.revwalk().ok()?;
+    revwalk.push_head().ok()?;
+    revwalk.hide(start).ok()?;
+    let done = revwalk.count();
+    // Once the stopped pick is committed by hand it counts as done but stays listed
+    let pending = ["CHERRY_PICK_HEAD", "REVERT_HEAD"]
+        .iter()
+        .any(|name| repo.path().join(name).exists());
+
+    Some(Rebase {
+        operation,
+        branch: None,
+        step: Some(done + pending as usize),
+        total: Some(done + todo.len() - !pending as usize),
+        onto: None,
+    })
+}
+
+// A local branch pointing at the commit, else a remote-tracking one, else its
+// abbreviated id
+fn name_commit(repo: &Repository, id: &str) -> Option<String> {
+    let id = Oid::from_str(id).ok()?;
+    for glob in ["refs/heads/*", "refs/remotes/*"] {
+        let named = repo
+            .references_glob(glob)
+            .ok()?
+            .flatten()
+            .find(|reference| {
+                reference.target() == Some(id)
+                    && !reference.name().unwrap_or_default().ends_with("/HEAD")
+            });
+        if let Some(reference) = named {
+            return reference.shorthand().map(|name| name.to_string());
+        }
+    }
+    let object = repo.find_object(id, None).ok()?;
+    let short_id = object.short_id().ok()?;
+    short_id.as_str().map(|id| id.to_string())
+}
+
+// An operation in progress wins over a detached HEAD, since rebases and bisects
+// detach HEAD themselves
+fn get_state(repo: &Repository, detached: bool) -> &'static str {
+    match repo.state() {
+        RepositoryState::Merge => "merge",
+        RepositoryState::Revert | RepositoryState::RevertSequence => "revert",
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "cherry-pick",
+        RepositoryState::Bisect => "bisect",
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge
+        | RepositoryState::ApplyMailboxOrRebase => "rebase",
+        RepositoryState::ApplyMailbox => "am",
+        RepositoryState::Clean if detached => "detached",
+        RepositoryState::Clean => "",
+    }
+}
+
+// Git lists the paths a merge, pick or rebase step stopped on at the end of MERGE_MSG,
+// as commented-out lines under "Conflicts:" (older versions left them uncommented).
+// Those still carrying conflict stages in the index are unresolved; the rest have been
+// resolved, whether by `git add` or `git rm`
+fn count_resolved_conflicts(repo: &Repository) -> Option<usize> {
+    let message = fs::read_to_string(repo.path().join("MERGE_MSG")).ok()?;
+    let mut lines = message.lines();
+    lines.find(|line| line.ends_with("Conflicts:"))?;
+    let conflicted: Vec<&str> = lines
+        .map_while(|line| {
+            let (comment, path) = line.split_once('\t')?;
+            (comment.chars().count() <= 1).then_some(path)
+        })
+        .collect();
+
+    let index = repo.index().ok()?;
+    let unresolved: Vec<Vec<u8>> = index
+        .conflicts()
+        .ok()?
+        .flatten()
+        .filter_map(|conflict| {
+            let entry = conflict.our.or(conflict.their).or(conflict.ancestor)?;
+            Some(entry.path)
+        })
+        .collect();
+    Some(
+        conflicted
+            .iter()
+            .filter(|path| !unresolved.iter().any(|entry| entry == path.as_bytes()))
+            .count(),
+    )
+}
 
-fn main() {
-    let repo = match Repository::discover(".") {
-        Ok(repo) => repo,
-        Err(_) => {
-            process::exit(1); // Exit with a non-zero status to indicate failure
+// Shown in place of the upstream name when its remote-tracking ref was deleted
+const GONE_UPSTREAM: &str = "[gone]";
+
+fn get_upstream_branch_name(repo: &Repository) -> Result<String, Error> {
+    let head = repo.head()?;
+    let branch = repo.find_branch(head.shorthand().unwrap_or_default(), BranchType::Local)?;
+    match branch.upstream() {
+        Ok(upstream) => {
+            let upstream_name = upstream.name()?.unwrap_or_default();
+            Ok(upstream_name.to_string())
+        }
+        // Tracking is configured but the remote-tracking ref no longer exists
+        Err(err) if err.code() == ErrorCode::NotFound && upstream_is_gone(repo, &head) => {
+            Ok(GONE_UPSTREAM.to_string())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// The branch.<name>.merge setting names the upstream as the remote itself calls it, so
+// `origin/release/1.0` comes back as `release/1.0` without guessing where the remote
+// name ends
+fn get_upstream_merge_branch(repo: &Repository) -> Option<String> {
+    let head = repo.head().ok()?;
+    let key = format!("branch.{}.merge", head.shorthand()?);
+    let merge = repo.config().ok()?.get_string(&key).ok()?;
+    Some(
+        merge
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&merge)
+            .to_string(),
+    )
+}
+
+// Walks back from HEAD until reaching history that some remote already has; with no
+// remotes at all, every commit counts as unpushed
+fn count_unpushed_commits(repo: &Repository) -> Result<usize, Error> {
+    let mut revwalk = repo.revwalk()?;
+    // Peeled first, since a revwalk can't start from a tag object HEAD was pointed at
+    revwalk.push(repo.head()?.peel_to_commit()?.id())?;
+    revwalk.hide_glob("refs/remotes/*")?;
+    Ok(revwalk.count())
+}
+
+// Measured against the tracked upstream, or origin's default branch under
+// --compare-default. In a shallow clone the boundary commits have no parents, so the
+// walk simply stops there and the counts are a lower bound rather than an error
+fn get_ahead_behind(repo: &Repository, options: &Options) -> Result<(usize, usize), Error> {
+    let (local, upstream) = get_comparison_commits(repo, options)?;
+    repo.graph_ahead_behind(local, upstream)
+}
+
+// Whether counting from either tip down to their merge base reaches a commit listed in
+// the shallow file, whose missing parents could hide more commits. Tips without a merge
+// base may only look unrelated because of the cut, so they count as crossing it
+fn crosses_shallow_boundary(repo: &Repository, local: Oid, upstream: Oid) -> bool {
+    let boundary = match fs::read_to_string(common_dir(repo).join("shallow")) {
+        Ok(contents) => contents,
+        Err(_) => return false,
+    };
+    let base = match repo.merge_base(local, upstream) {
+        Ok(base) => base,
+        Err(_) => return true,
+    };
+    let contains =
+        |tip: Oid, id: Oid| tip == id || repo.graph_descendant_of(tip, id).unwrap_or(false);
+    boundary
+        .lines()
+        .filter_map(|line| Oid::from_str(line.trim()).ok())
+        .any(|id| (contains(local, id) || contains(upstream, id)) && !contains(base, id))
+}
+
+// For fork workflows: every remote-tracking ref named like the current branch.
+// Remotes without such a branch are left out
+fn get_all_remotes_ahead_behind(repo: &Repository) -> Result<Vec<RemoteDivergence>, Error> {
+    let head = repo.head()?;
+    // A detached HEAD's shorthand is `HEAD`, which would match each remote's HEAD
+    if !head.is_branch() {
+        return Ok(Vec::new());
+    }
+    let local = head.peel_to_commit()?.id();
+    let branch = head.shorthand().unwrap_or_default();
+
+    let mut counts = Vec::new();
+    for remote in repo.remotes()?.iter().flatten() {
+        let refname = format!("refs/remotes/{}/{}", remote, branch);
+        let remote_commit = match repo
+            .find_reference(&refname)
+            .and_then(|reference| reference.peel_to_commit())
+        {
+            Ok(commit) => commit.id(),
+            Err(_) => continue,
+        };
+        counts.push((
+            remote.to_string(),
+            repo.graph_ahead_behind(local, remote_commit)?,
+            crosses_shallow_boundary(repo, local, remote_commit),
+        ));
+    }
+    Ok(counts)
+}
+
+// Where HEAD forked from the branch it's compared against; an error when the two
+// share no history
+fn get_merge_base(repo: &Repository, options: &Options) -> Result<String, Error> {
+    let (local, upstream) = get_comparison_commits(repo, options)?;
+    let base = repo.find_object(repo.merge_base(local, upstream)?, None)?;
+    let short_id = base.short_id()?;
+    Ok(short_id.as_str().unwrap_or_default().to_string())
+}
+
+// Everything the branch has changed so far, committed, staged or not: files that differ
+// between the merge base's tree and the working tree. Untracked files aren't included
+fn count_changes_since_fork(repo: &Repository, options: &Options) -> Result<usize, Error> {
+    let (local, upstream) = get_comparison_commits(repo, options)?;
+    let base = repo.find_commit(repo.merge_base(local, upstream)?)?;
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&base.tree()?), None)?;
+    Ok(diff.deltas().len())
+}
+
+// HEAD and the commit get_ahead_behind measures it against
+fn get_comparison_commits(repo: &Repository, options: &Options) -> Result<(Oid, Oid), Error> {
+    let head = repo.head()?;
+    let local = head.peel_to_commit()?.id();
+    let tracked = repo
+        .find_branch(head.shorthand().unwrap_or_default(), BranchType::Local)
+        .and_then(|branch| branch.upstream());
+    let upstream = match tracked {
+        Ok(upstream) => upstream.into_reference(),
+        Err(_) if options.compare_default => {
+            repo.find_reference("refs/remotes/origin/HEAD")?.resolve()?
         }
+        Err(err) => return Err(err),
     };
+    Ok((local, upstream.peel_to_commit()?.id()))
+}
+
+// Whether the branch could be deleted without losing work: HEAD is origin's default
+// branch tip or one of its ancestors
+fn is_merged_into_default(repo: &Repository) -> Result<bool, Error> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let default = repo
+        .find_reference("refs/remotes/origin/HEAD")?
+        .peel_to_commit()?
+        .id();
+    Ok(head == default || repo.graph_descendant_of(default, head)?)
+}
 
-    let mut components = Vec::new();
+// Resolves `refs/remotes/origin/HEAD`, e.g. to `origin/main`
+fn get_default_branch_name(repo: &Repository) -> Result<String, Error> {
+    let default = repo.find_reference("refs/remotes/origin/HEAD")?.resolve()?;
+    Ok(default.shorthand().unwrap_or_default().to_string())
+}
 
-    // Get current branch name and add it to components if present
-    if let Ok(branch_name) = get_current_branch_name(&repo) {
-        components.push(branch_name);
+fn upstream_is_gone(repo: &Repository, head: &Reference) -> bool {
+    let refname = head.name().unwrap_or_default();
+    match repo.branch_upstream_name(refname) {
+        Ok(upstream) => match upstream.as_str() {
+            Some(upstream) => repo.find_reference(upstream).is_err(),
+            None => false,
+        },
+        Err(_) => false,
     }
+}
+
+// Abbreviated to `core.abbrev` characters, or as many as needed to stay unique
+fn get_head_hash(repo: &Repository) -> Result<String, Error> {
+    let commit = repo.head()?.peel_to_commit()?;
+    let short_id = commit.as_object().short_id()?;
+    Ok(short_id.as_str().unwrap_or_default().to_string())
+}
 
-    //// Get upstream branch name and add it to components if present
-    if let Ok(upstream_name) = get_upstream_branch_name(&repo) {
-        components.push(upstream_name);
+// `N` when HEAD carries no signature at all. Checking one needs gpg (or ssh-keygen for
+// SSH signatures) set up the way `git verify-commit` expects: a good signature is `G`,
+// a bad one `B`, and anything that can't be checked, such as a missing public key or
+// an unborn HEAD, is `?`
+fn get_signature_status(repo: &Repository) -> &'static str {
+    let id = match repo.head().and_then(|head| head.peel_to_commit()) {
+        Ok(commit) => commit.id(),
+        Err(_) => return "?",
+    };
+    match repo.extract_signature(&id, None) {
+        Ok(_) => {}
+        Err(err) if err.code() == ErrorCode::NotFound => return "N",
+        Err(_) => return "?",
     }
 
-    // Get git status message and add it to components if present
-    let status_message = get_git_status();
-    if !status_message.is_empty() {
-        components.push(status_message);
+    let output = git_command()
+        .args(["verify-commit", "--raw", &id.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => "G",
+        Ok(output) if String::from_utf8_lossy(&output.stderr).contains("[GNUPG:] BADSIG") => "B",
+        _ => "?",
     }
+}
 
-    // Join all components with a space, ensuring no extra spaces if a section is empty
-    let output = components.join(" ");
-    println!("{}", output);
+fn get_last_commit(repo: &Repository) -> Result<String, Error> {
+    let commit = repo.head()?.peel_to_commit()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+    Ok(format!(
+        "{} {}",
+        get_head_hash(repo)?,
+        format_age(now.saturating_sub(commit.time().seconds()))
+    ))
 }
 
-fn get_current_branch_name(repo: &Repository) -> Result<String, Error> {
-    let head = repo.head()?;
-    if head.is_branch() {
-        let shorthand = head.shorthand().unwrap_or("unknown branch");
-        Ok(shorthand.to_string())
+// FETCH_HEAD is rewritten by every fetch (and pull), so its mtime is when the repo last
+// heard from a remote
+fn get_fetch_age(repo: &Repository) -> Option<i64> {
+    let modified = fs::metadata(repo.path().join("FETCH_HEAD"))
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    let elapsed = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    Some(elapsed.as_secs() as i64)
+}
+
+// FETCH_HEAD lines are `<id>\t<not-for-merge or empty>\t<description>`; the ones
+// without `not-for-merge` are what a plain `git merge FETCH_HEAD` or pull would bring in
+fn count_unmerged_fetched(repo: &Repository) -> Result<usize, Error> {
+    let fetch_head = fs::read_to_string(repo.path().join("FETCH_HEAD")).unwrap_or_default();
+    let mut revwalk = repo.revwalk()?;
+    let mut any = false;
+    for line in fetch_head.lines() {
+        let mut fields = line.split('\t');
+        let (id, merge) = (fields.next(), fields.next());
+        if let (Some(id), Some("")) = (id, merge) {
+            revwalk.push(Oid::from_str(id)?)?;
+            any = true;
+        }
+    }
+    if !any {
+        return Ok(0);
+    }
+    revwalk.hide(repo.head()?.peel_to_commit()?.id())?;
+    Ok(revwalk.count())
+}
+
+// Commit times are seconds since the epoch in UTC, so the author's timezone offset
+// never enters into the age. Clock skew can still put a commit in the future, which
+// reads as "just now" rather than a negative age
+fn format_age(seconds: i64) -> String {
+    let (value, unit) = match seconds {
+        s if s <= 0 => return "just now".to_string(),
+        s if s < 60 => (s, "s"),
+        s if s < 60 * 60 => (s / 60, "m"),
+        s if s < 60 * 60 * 24 => (s / (60 * 60), "h"),
+        s if s < 60 * 60 * 24 * 30 => (s / (60 * 60 * 24), "d"),
+        s if s < 60 * 60 * 24 * 365 => (s / (60 * 60 * 24 * 30), "mo"),
+        s => (s / (60 * 60 * 24 * 365), "y"),
+    };
+    format!("{}{} ago", value, unit)
+}
+
+// Every stash entry is a line in the `refs/stash` reflog, wherever the ref itself is
+// stored. A stash ref without a reflog (e.g. copied in by hand) still counts as one.
+// Returns (entries made on `branch`, all entries)
+fn count_stashes(repo: &Repository, branch: Option<&str>) -> (usize, usize) {
+    match repo.reflog("refs/stash") {
+        Ok(reflog) if !reflog.is_empty() => {
+            let on_branch = reflog
+                .iter()
+                .filter(|entry| {
+                    let made_on = entry
+                        .message()
+                        .and_then(parse_stash_message)
+                        .map(|(branch, _)| branch);
+                    made_on.is_some() && made_on == branch
+                })
+                .count();
+            (on_branch, reflog.len())
+        }
+        _ if repo.find_reference("refs/stash").is_ok() => (0, 1),
+        _ => (0, 0),
+    }
+}
+
+// `git stash` records "WIP on <branch>: ..." or, with a message, "On <branch>: ...",
+// split here into the branch and the rest. Anything else (hand-edited or foreign
+// reflogs) belongs to no branch
+fn parse_stash_message(message: &str) -> Option<(&str, &str)> {
+    let rest = message
+        .strip_prefix("WIP on ")
+        .or_else(|| message.strip_prefix("On "))?;
+    let (branch, text) = rest.split_once(':')?;
+    Some((branch, text.trim_start()))
+}
+
+// The newest stash in the chosen --stash-scope, without its "On <branch>:" prefix and
+// cut to --stash-message-length characters
+fn get_stash_message(repo: &Repository, options: &Options, branch: Option<&str>) -> Option<String> {
+    let reflog = repo.reflog("refs/stash").ok()?;
+    let message = reflog.iter().find_map(|entry| {
+        let message = entry.message()?.to_string();
+        match parse_stash_message(&message) {
+            Some((made_on, _)) if options.stash_current_branch && Some(made_on) != branch => None,
+            Some((_, text)) => Some(text.to_string()),
+            None if options.stash_current_branch => None,
+            None => Some(message),
+        }
+    })?;
+    match options.stash_message_length {
+        Some(length) if message.chars().count() > length => {
+            let truncated: String = message.chars().take(length).collect();
+            Some(truncated + options.symbols().ellipsis)
+        }
+        _ => Some(message),
+    }
+}
+
+// Runs a user script with the worktree root (or git dir, when bare) as its only argument
+// and keeps the first line it prints. A prompt can't wait on a slow or broken script, so
+// failures, empty output and anything still running after the timeout yield nothing
+fn run_status_hook(repo: &Repository, hook: &str) -> Option<String> {
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    let root = work_tree(repo).unwrap_or_else(|| repo.path().to_path_buf());
+    let mut child = Command::new(hook)
+        .arg(root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break,
+            Ok(None) if started.elapsed() < TIMEOUT => thread::sleep(Duration::from_millis(5)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            _ => return None,
+        }
+    }
+
+    let mut output = String::new();
+    child.stdout.take()?.read_to_string(&mut output).ok()?;
+    let line = output.lines().next()?.trim_end();
+    if line.is_empty() {
+        None
     } else {
-        Ok("HEAD is detached".to_string())
+        Some(line.to_string())
     }
 }
 
-fn get_upstream_branch_name(repo: &Repository) -> Result<String, Error> {
-    let head = repo.head()?;
-    let branch = repo.find_branch(head.shorthand().unwrap_or_default(), BranchType::Local)?;
-    let upstream = branch.upstream()?;
-    let upstream_name = upstream.name()?.unwrap_or_default();
-    Ok(upstream_name.to_string())
+// Submodules listed in .gitmodules but never cloned are left out of the total
+fn get_submodule_counts(repo: &Repository) -> Result<(usize, usize), Error> {
+    let dirty_flags = SubmoduleStatus::WD_MODIFIED
+        | SubmoduleStatus::WD_INDEX_MODIFIED
+        | SubmoduleStatus::WD_WD_MODIFIED
+        | SubmoduleStatus::WD_UNTRACKED;
+    let mut dirty = 0;
+    let mut total = 0;
+
+    for submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or_default();
+        let status = repo.submodule_status(name, SubmoduleIgnore::Unspecified)?;
+        if status.is_wd_uninitialized() || !status.is_in_wd() {
+            continue;
+        }
+        total += 1;
+        if status.intersects(dirty_flags) {
+            dirty += 1;
+        }
+    }
+
+    Ok((dirty, total))
+}
+
+// Walks the HEAD reflog for `checkout: moving from X to Y` entries, newest first, and
+// returns up to `count` distinct local branches that were checked out before the
+// current one
+fn get_recent_branches(repo: &Repository, count: usize) -> Vec<String> {
+    let current = get_current_branch_name(repo).unwrap_or_default();
+    let reflog = match repo.reflog("HEAD") {
+        Ok(reflog) => reflog,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut branches: Vec<String> = Vec::new();
+    for entry in reflog.iter() {
+        if branches.len() >= count {
+            break;
+        }
+        let message = entry.message().unwrap_or_default();
+        let from = match message
+            .strip_prefix("checkout: moving from ")
+            .and_then(|rest| rest.split_once(" to "))
+        {
+            Some((from, _)) => from,
+            None => continue,
+        };
+        // Skips detached commits and branches that have since been deleted
+        if from == current
+            || branches.iter().any(|branch| branch == from)
+            || repo.find_branch(from, BranchType::Local).is_err()
+        {
+            continue;
+        }
+        branches.push(from.to_string());
+    }
+
+    branches
 }
 
-fn get_git_status() -> String {
-    let output = Command::new("git")
-        .args(["status", "--porcelain", "-uno"])
+fn get_git_status(repo: &Repository, options: &Options) -> Option<ChangesSummary> {
+    if let Some(base) = &options.base {
+        let target = options.target.as_deref().unwrap_or("HEAD");
+        return get_tree_diff_summary(repo, base, target).ok();
+    }
+    if options.fast_clean && !options.include_assume_unchanged && looks_clean(repo) {
+        return Some(ChangesSummary {
+            fast_clean: true,
+            ..ChangesSummary::default()
+        });
+    }
+
+    let untracked = format!("-u{}", get_untracked_mode(repo, options));
+    // A staged move edited past the threshold shows as a deletion plus an addition, two
+    // staged entries instead of one rename
+    let renames = options
+        .rename_threshold
+        .map(|threshold| format!("--find-renames={}%", threshold));
+    let output = git_command()
+        .args(["status", "--porcelain", &untracked])
+        .args(renames)
+        .args(pathspec_args(options))
+        .stderr(Stdio::piped())
         .output();
 
     match output {
-        Ok(output) => {
-            if output.stdout.is_empty() {
-                "✓".to_string()
-            } else {
-                parse_git_status_output(String::from_utf8_lossy(&output.stdout))
+        Ok(output) if output.status.success() => {
+            let mut summary = parse_git_status_output(String::from_utf8_lossy(&output.stdout));
+            let by_dir = options
+                .by_dir
+                .or_else(|| options.template_uses("by_dir").then_some(1));
+            if let Some(depth) = by_dir {
+                summary.by_dir = group_by_dir(&String::from_utf8_lossy(&output.stdout), depth);
             }
+            // git skips unreadable directories with a warning rather than failing
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let warning = stderr
+                .lines()
+                .find(|line| line.starts_with("warning: could not open directory"));
+            summary.incomplete = warning.is_some();
+            if let Some(warning) = warning.filter(|_| options.strict) {
+                fail("io", warning.trim_start_matches("warning: "));
+            }
+            if options.include_assume_unchanged {
+                count_hidden_changes(repo, &mut summary, options);
+            }
+            Some(summary)
+        }
+        // --strict: a status that can't be trusted fails the whole run
+        Ok(output) if options.strict => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            fail("git", stderr.lines().next().unwrap_or("git status failed"));
+        }
+        Err(err) if options.strict => fail("io", &format!("failed to run git: {}", err)),
+        _ => None,
+    }
+}
+
+// Tracked files --fast-clean stats, spread evenly through the index
+const FAST_CLEAN_SAMPLE: usize = 64;
+
+// --fast-clean: a few cheap checks that, when they all pass, stand in for running
+// git status. No operation or conflict is in progress, the index matches HEAD's tree,
+// a sample of tracked files still has the size and mtime the index recorded, and
+// nothing at the top of the work tree is newer than the index. This can report clean
+// wrongly when the only edits are to files outside the sample that kept their size, or
+// when new untracked files sit below the top-level directories
+fn looks_clean(repo: &Repository) -> bool {
+    check_looks_clean(repo).unwrap_or(false)
+}
+
+fn check_looks_clean(repo: &Repository) -> Option<bool> {
+    let workdir = work_tree(repo)?;
+    let index = repo.index().ok()?;
+    if repo.state() != RepositoryState::Clean || index.has_conflicts() {
+        return Some(false);
+    }
+    let head_tree = repo.head().ok()?.peel_to_tree().ok()?;
+    let staged = repo
+        .diff_tree_to_index(Some(&head_tree), Some(&index), None)
+        .ok()?;
+    if staged.deltas().len() > 0 {
+        return Some(false);
+    }
+
+    let step = (index.len() / FAST_CLEAN_SAMPLE).max(1);
+    for entry in index.iter().step_by(step) {
+        let path = workdir.join(String::from_utf8_lossy(&entry.path).as_ref());
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Some(false),
+        };
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        if metadata.len() as u32 != entry.file_size
+            || modified.as_secs() as i64 != entry.mtime.seconds() as i64
+        {
+            return Some(false);
+        }
+    }
+
+    let index_written = fs::metadata(repo.path().join("index"))
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    let newer = fs::read_dir(workdir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name() != ".git")
+        .any(|entry| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified > index_written)
+        });
+    Some(!newer)
+}
+
+// --base/--target: the same summary, but for the committed difference between two
+// revisions. Trees have no untracked or conflicted files, so additions, modifications,
+// renames and type changes all count as updated
+fn get_tree_diff_summary(
+    repo: &Repository,
+    base: &str,
+    target: &str,
+) -> Result<ChangesSummary, Error> {
+    let base = repo.revparse_single(base)?.peel_to_tree()?;
+    let target = repo.revparse_single(target)?.peel_to_tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&base), Some(&target), None)?;
+
+    let mut summary = ChangesSummary::default();
+    for delta in diff.deltas() {
+        match delta.status() {
+            Delta::Deleted => summary.deleted += 1,
+            Delta::Unmodified | Delta::Ignored | Delta::Untracked | Delta::Unreadable => {}
+            _ => summary.updated += 1,
         }
-        Err(_) => "Failed to get status".to_string(),
+    }
+    Ok(summary)
+}
+
+// `--pathspec` values are plain path prefixes relative to the current directory, so
+// they're passed as literal pathspecs and glob characters match themselves. `--exclude`
+// patterns are fnmatch-style from the repository root, where `*` also matches `/`, and
+// only hide paths from gitstatus's counts; git itself is unaffected
+fn pathspec_args(options: &Options) -> Vec<String> {
+    if options.pathspecs.is_empty() && options.excludes.is_empty() {
+        return Vec::new();
+    }
+
+    let mut args = vec!["--".to_string()];
+    args.extend(
+        options
+            .pathspecs
+            .iter()
+            .map(|path| format!(":(literal){}", path)),
+    );
+    args.extend(
+        options
+            .excludes
+            .iter()
+            .map(|pattern| format!(":(top,exclude){}", pattern)),
+    );
+    args
+}
+
+// A skip-worktree entry with staged changes is easy to commit by accident, since
+// neither git status nor a sparse checkout will show the file. Paths are relative
+// to the repository root
+fn get_staged_skip_worktree_paths() -> Vec<String> {
+    let run = |args: &[&str]| {
+        git_command()
+            .args(args)
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+            .unwrap_or_default()
+    };
+
+    let listing = run(&["ls-files", "-z", "-v", "--full-name", "--", ":/"]);
+    let skip_worktree: Vec<&str> = listing
+        .split('\0')
+        .filter_map(|record| record.split_once(' '))
+        .filter(|(tag, _)| tag.eq_ignore_ascii_case("s"))
+        .map(|(_, path)| path)
+        .collect();
+    if skip_worktree.is_empty() {
+        return Vec::new();
+    }
+
+    run(&["diff", "--cached", "--name-only", "-z"])
+        .split('\0')
+        .filter(|path| skip_worktree.contains(path))
+        .map(|path| path.to_string())
+        .collect()
+}
+
+// The command line wins over `status.showUntrackedFiles`, which wins over the built-in
+// `no`
+fn get_untracked_mode(repo: &Repository, options: &Options) -> String {
+    if let Some(mode) = &options.untracked_files {
+        return mode.clone();
+    }
+
+    let configured = repo
+        .config()
+        .and_then(|config| config.get_string("status.showUntrackedFiles"));
+    match configured
+        .as_deref()
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        Ok("normal") | Ok("true") | Ok("yes") | Ok("on") | Ok("1") => "normal".to_string(),
+        Ok("all") => "all".to_string(),
+        _ => "no".to_string(),
     }
 }
 
-fn parse_git_status_output(output: std::borrow::Cow<str>) -> String {
-    let mut updated = 0;
-    let mut deleted = 0;
-    let mut untracked = 0;
+fn parse_git_status_output(output: std::borrow::Cow<str>) -> ChangesSummary {
+    let mut summary = ChangesSummary {
+        entries: Some(output.lines().count()),
+        ..ChangesSummary::default()
+    };
 
     for line in output.lines() {
-        match &line[0..2] {
-            " M" => updated += 1,
-            " D" => deleted += 1,
-            "??" => untracked += 1,
+        let status = match line.get(0..2) {
+            Some(status) => status.as_bytes(),
+            None => continue,
+        };
+        match status {
+            // Unmerged paths: both-deleted, added-by-us, deleted-by-them, added-by-them,
+            // deleted-by-us, both-added and both-modified
+            b"DD" | b"AU" | b"UD" | b"UA" | b"DU" | b"AA" | b"UU" => summary.conflicts += 1,
+            b"??" => {
+                summary.untracked += 1;
+                if line.ends_with('/') || line.ends_with("/\"") {
+                    summary.untracked_dirs += 1;
+                }
+            }
+            // The first column is the index side and the second the worktree side, so
+            // `MM` is both staged and unstaged, though only ` M` and ` D` feed the
+            // modified and deleted markers
+            &[index, worktree] => {
+                if matches!(index, b'M' | b'A' | b'D' | b'R' | b'C' | b'T') {
+                    summary.staged += 1;
+                }
+                if matches!(worktree, b'M' | b'T' | b'D') {
+                    summary.unstaged += 1;
+                }
+                match status {
+                    b" M" => summary.updated += 1,
+                    b" D" => summary.deleted += 1,
+                    _ => {}
+                }
+            }
             _ => {}
         }
     }
 
-    let mut message = String::new();
-    if updated > 0 {
-        message += &format!("+{}", updated);
+    summary
+}
+
+// The porcelain entries the change markers count (conflicts, ` M`, ` D` and `??`),
+// under their first `depth` directories, so the groups add up to the markers. Files
+// above that depth count towards the directory they're in, `.` at the top, and an
+// untracked directory listed as `dir/` counts as being inside itself
+fn group_by_dir(output: &str, depth: usize) -> Vec<(String, usize)> {
+    let mut groups = BTreeMap::new();
+    for line in output.lines().filter(|line| line.len() > 3) {
+        let counted = matches!(
+            &line.as_bytes()[..2],
+            b"DD" | b"AU" | b"UD" | b"UA" | b"DU" | b"AA" | b"UU" | b" M" | b" D" | b"??"
+        );
+        if !counted {
+            continue;
+        }
+        // Renames and copies are `old -> new`, and the change shows up at the new path
+        let path = line[3..].rsplit(" -> ").next().unwrap_or_default();
+        let path = path.trim_matches('"');
+        let mut dirs: Vec<_> = path.split('/').collect();
+        dirs.pop();
+        let prefix = if dirs.is_empty() {
+            ".".to_string()
+        } else {
+            dirs[..dirs.len().min(depth)].join("/")
+        };
+        *groups.entry(prefix).or_insert(0) += 1;
+    }
+    groups.into_iter().collect()
+}
+
+// `git status` never reports files marked assume-unchanged or skip-worktree, so compare
+// those entries against the worktree ourselves: `git ls-files -v` tags them with a
+// lowercase letter or `S`, and `git hash-object` applies the same filters git would
+fn count_hidden_changes(repo: &Repository, summary: &mut ChangesSummary, options: &Options) {
+    let root = match work_tree(repo) {
+        Some(root) => root,
+        None => return,
+    };
+    // Unlike git status, ls-files only lists the current directory unless told otherwise
+    let mut pathspecs = pathspec_args(options);
+    if options.pathspecs.is_empty() {
+        if pathspecs.is_empty() {
+            pathspecs.push("--".to_string());
+        }
+        pathspecs.insert(1, ":/".to_string());
+    }
+    let output = match git_command()
+        .args(["ls-files", "-z", "-s", "-v", "--full-name"])
+        .args(pathspecs)
+        .output()
+    {
+        Ok(output) => output,
+        Err(_) => return,
+    };
+    let listing = String::from_utf8_lossy(&output.stdout);
+
+    let mut expected = Vec::new();
+    let mut paths = String::new();
+    for record in listing.split('\0') {
+        let (meta, path) = match record.split_once('\t') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        // <tag> <mode> <object> <stage>
+        let fields: Vec<&str> = meta.split(' ').collect();
+        if fields.len() != 4 {
+            continue;
+        }
+        let (tag, mode, object) = (fields[0], fields[1], fields[2]);
+        let hidden = tag == "S" || tag.chars().all(|c| c.is_ascii_lowercase());
+        // Symlinks and submodules can't be compared by hashing the file contents
+        if !hidden || mode == "120000" || mode == "160000" {
+            continue;
+        }
+
+        let path = root.join(path);
+        if !path.exists() {
+            // Skip-worktree files are absent by design, e.g. outside a sparse checkout,
+            // so only a missing assume-unchanged file has really been deleted
+            if tag != "S" && tag != "s" {
+                summary.deleted += 1;
+                summary.unstaged += 1;
+            }
+            continue;
+        }
+        expected.push(object.to_string());
+        paths += &path.to_string_lossy();
+        paths.push('\n');
+    }
+
+    if expected.is_empty() {
+        return;
+    }
+
+    let child = git_command()
+        .args(["hash-object", "--stdin-paths"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let output = match child {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(paths.as_bytes());
+            }
+            match child.wait_with_output() {
+                Ok(output) => output,
+                Err(_) => return,
+            }
+        }
+        Err(_) => return,
+    };
+
+    let actual = String::from_utf8_lossy(&output.stdout);
+    for (expected, actual) in expected.iter().zip(actual.lines()) {
+        if expected != actual {
+            summary.updated += 1;
+            summary.unstaged += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{RepositoryInitOptions, Signature};
+    use std::path::{Path, PathBuf};
+
+    // A throwaway repository with one commit on `main`, removed again on drop
+    struct Fixture {
+        repo: Repository,
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("gitstatus-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let repo =
+                Repository::init_opts(&dir, RepositoryInitOptions::new().initial_head("main"))
+                    .unwrap();
+            let fixture = Fixture { repo, dir };
+            fixture.commit("file", "one\n");
+            fixture
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(name)
+        }
+
+        fn write(&self, name: &str, contents: &str) {
+            fs::write(self.path(name), contents).unwrap();
+        }
+
+        // Writes a state file under the git dir, creating its directories
+        fn write_git(&self, name: &str, contents: &str) {
+            let path = self.repo.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn commit(&self, name: &str, contents: &str) -> Oid {
+            self.write(name, contents);
+            let mut index = self.repo.index().unwrap();
+            index.add_path(Path::new(name)).unwrap();
+            index.write().unwrap();
+            let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = self
+                .repo
+                .head()
+                .ok()
+                .and_then(|head| head.peel_to_commit().ok());
+            let parents: Vec<_> = parent.iter().collect();
+            let signature = signature();
+            self.repo
+                .commit(Some("HEAD"), &signature, &signature, name, &tree, &parents)
+                .unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // The git subprocesses and is_inside_git_dir work from the current directory, which
+    // every test thread shares
+    fn in_dir<T>(dir: &Path, run: impl FnOnce() -> T) -> T {
+        static CURRENT_DIR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = CURRENT_DIR.lock().unwrap_or_else(|err| err.into_inner());
+        let previous = env::current_dir().ok();
+        env::set_current_dir(dir).unwrap();
+        let result = run();
+        if let Some(previous) = previous {
+            let _ = env::set_current_dir(previous);
+        }
+        result
+    }
+
+    fn signature() -> Signature<'static> {
+        Signature::now("gitstatus", "gitstatus@example.com").unwrap()
+    }
+
+    // A status tracking origin/main with the given divergence, None for no upstream
+    fn tracking(ahead_behind: Option<(usize, usize)>) -> GitStatus {
+        GitStatus {
+            branch: Some("main".to_string()),
+            upstream: ahead_behind.map(|_| "origin/main".to_string()),
+            ahead_behind,
+            marker_order: MARKER_ORDER.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn future_commit_times_read_as_just_now() {
+        assert_eq!(format_age(-3600), "just now");
+        assert_eq!(format_age(0), "just now");
+        assert_eq!(format_age(59), "59s ago");
+        assert_eq!(format_age(2 * 60 * 60), "2h ago");
+        assert_eq!(format_age(400 * 24 * 60 * 60), "1y ago");
+    }
+
+    #[test]
+    fn divergence_exit_codes() {
+        assert_eq!(divergence_exit_code(Some((0, 0))), 0);
+        assert_eq!(divergence_exit_code(Some((2, 0))), 10);
+        assert_eq!(divergence_exit_code(Some((0, 3))), 11);
+        assert_eq!(divergence_exit_code(Some((2, 3))), 12);
+        assert_eq!(divergence_exit_code(None), 13);
+    }
+
+    fn parse(output: &str) -> ChangesSummary {
+        parse_git_status_output(output.into())
+    }
+
+    #[test]
+    fn index_and_worktree_columns_count_separately() {
+        let summary = parse("MM both\nM  staged\n T typechange\nAD gone\n?? new\n");
+        assert_eq!(summary.staged, 3);
+        assert_eq!(summary.unstaged, 3);
+        // The markers keep counting only entries whose index side is unchanged
+        assert_eq!(summary.updated, 0);
+        assert_eq!(summary.deleted, 0);
+        assert_eq!(summary.untracked, 1);
+        assert_eq!(summary.entries, Some(5));
+    }
+
+    #[test]
+    fn markers_follow_the_marker_order() {
+        let summary = parse("UU a\n M b\n M c\n?? d\n");
+        assert_eq!(
+            summary.format(&UNICODE_SYMBOLS, false, MARKER_ORDER, None),
+            "!1+2n1"
+        );
+        assert_eq!(
+            summary.format(&UNICODE_SYMBOLS, false, "umc", None),
+            "n1+2!1"
+        );
+        assert_eq!(summary.format(&ASCII_SYMBOLS, true, "dum", None), "D0U1M2");
+        assert_eq!(parse("").format(&UNICODE_SYMBOLS, false, "cmdu", None), "✓");
+    }
+
+    #[test]
+    fn inline_stash_marker_follows_the_marker_order() {
+        let summary = parse("UU a\n M b\n");
+        let stash = Some("$2");
+        assert_eq!(
+            summary.format(&UNICODE_SYMBOLS, false, "scmdu", stash),
+            "$2!1+1"
+        );
+        assert_eq!(
+            summary.format(&UNICODE_SYMBOLS, false, "cmsdu", stash),
+            "!1+1$2"
+        );
+        assert_eq!(
+            summary.format(&UNICODE_SYMBOLS, false, "cmsdu", None),
+            "!1+1"
+        );
+        let clean = parse("");
+        assert_eq!(clean.format(&UNICODE_SYMBOLS, false, "scmdu", stash), "$2✓");
+        assert_eq!(clean.format(&UNICODE_SYMBOLS, false, "cmdus", stash), "✓$2");
+    }
+
+    #[test]
+    fn unmerged_pairs_are_only_conflicts() {
+        let summary = parse("UU a\nAA b\nDU c\n");
+        assert_eq!(summary.conflicts, 3);
+        assert_eq!(summary.staged + summary.unstaged, 0);
+    }
+
+    #[test]
+    fn collapsed_untracked_directories() {
+        let summary = parse("?? file\n?? dir/\n?? \"odd dir/\"\n");
+        assert_eq!(summary.untracked, 3);
+        assert_eq!(summary.untracked_dirs, 2);
+    }
+
+    fn groups(expected: &[(&str, usize)]) -> Vec<(String, usize)> {
+        expected
+            .iter()
+            .map(|&(dir, count)| (dir.to_string(), count))
+            .collect()
+    }
+
+    #[test]
+    fn groups_changes_by_leading_directories() {
+        let output = " M pkg-a/src/x\n M pkg-a/y\nUU pkg-b/d\n M top\n?? pkg-c/new/\n";
+        assert_eq!(
+            group_by_dir(output, 1),
+            groups(&[(".", 1), ("pkg-a", 2), ("pkg-b", 1), ("pkg-c", 1)])
+        );
+        // Staged-only entries aren't in the markers, so they aren't grouped either
+        assert_eq!(group_by_dir("R  a/b -> c/d\nM  e/f\n", 1), groups(&[]));
+        assert_eq!(
+            group_by_dir(output, 2),
+            groups(&[
+                (".", 1),
+                ("pkg-a", 1),
+                ("pkg-a/src", 1),
+                ("pkg-b", 1),
+                ("pkg-c/new", 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn stash_messages_split_into_branch_and_text() {
+        assert_eq!(
+            parse_stash_message("WIP on main: 1a2b3c4 Fix it"),
+            Some(("main", "1a2b3c4 Fix it"))
+        );
+        assert_eq!(
+            parse_stash_message("On feature/x: half done"),
+            Some(("feature/x", "half done"))
+        );
+        assert_eq!(parse_stash_message("made by hand"), None);
+    }
+
+    #[test]
+    fn stash_counts_come_from_the_stash_reflog() {
+        let mut fixture = Fixture::new("stashes");
+        assert_eq!(count_stashes(&fixture.repo, Some("main")), (0, 0));
+
+        for change in ["two\n", "three\n", "four\n"] {
+            fixture.write("file", change);
+            fixture.repo.stash_save(&signature(), "wip", None).unwrap();
+            if change == "two\n" {
+                assert_eq!(count_stashes(&fixture.repo, Some("main")), (1, 1));
+            }
+        }
+        assert_eq!(count_stashes(&fixture.repo, Some("main")), (3, 3));
+        assert_eq!(count_stashes(&fixture.repo, Some("other")), (0, 3));
+
+        // A stash ref without a reflog still shows up as a single entry
+        fixture.repo.reflog_delete("refs/stash").unwrap();
+        assert_eq!(count_stashes(&fixture.repo, Some("main")), (0, 1));
+    }
+
+    #[test]
+    fn help_fits_in_eighty_columns() {
+        let help = help_text();
+        for line in help.lines() {
+            assert!(line.chars().count() <= 80, "{}", line);
+        }
+        for name in TEMPLATE_VOCABULARY.tokens {
+            assert!(help.contains(&format!(" {}", name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn tracking_token_covers_every_upstream_state() {
+        assert_eq!(tracking(Some((0, 0))).token("tracking"), "");
+        assert_eq!(tracking(Some((3, 0))).token("tracking"), "↑3");
+        assert_eq!(tracking(Some((3, 2))).token("tracking"), "↑3↓2");
+        assert_eq!(tracking(None).token("tracking"), "");
+
+        let gone = GitStatus {
+            upstream: Some(GONE_UPSTREAM.to_string()),
+            ..tracking(None)
+        };
+        assert_eq!(gone.token("tracking"), "[gone]");
+
+        let signed = GitStatus {
+            divergence_style: DivergenceStyle::Signed,
+            ..tracking(Some((3, 2)))
+        };
+        assert_eq!(signed.token("tracking"), "+3/-2");
+    }
+
+    #[test]
+    fn action_token_names_what_to_do() {
+        let words = || {
+            [
+                "pull".to_string(),
+                "push".to_string(),
+                "diverged".to_string(),
+            ]
+        };
+        let action = |ahead_behind| {
+            let status = GitStatus {
+                action_words: words(),
+                ..tracking(ahead_behind)
+            };
+            status.token("action")
+        };
+        assert_eq!(action(Some((0, 2))), "pull");
+        assert_eq!(action(Some((2, 0))), "push");
+        assert_eq!(action(Some((2, 2))), "diverged");
+        assert_eq!(action(Some((0, 0))), "");
+        assert_eq!(action(None), "");
+    }
+
+    #[test]
+    fn divergence_tokens_are_empty_without_an_upstream() {
+        let in_sync = tracking(Some((0, 0)));
+        assert_eq!(in_sync.token("ahead"), "0");
+        assert_eq!(in_sync.token("behind"), "0");
+        assert_eq!(in_sync.token("divergence"), "");
+
+        let none = tracking(None);
+        assert_eq!(none.token("ahead"), "");
+        assert_eq!(none.token("behind"), "");
+        assert_eq!(none.token("divergence"), "");
+
+        let signed = |ahead_behind| GitStatus {
+            divergence_style: DivergenceStyle::Signed,
+            ..tracking(ahead_behind)
+        };
+        assert_eq!(signed(Some((0, 0))).token("divergence"), "+0/-0");
+        assert_eq!(signed(None).token("divergence"), "");
+    }
+
+    #[test]
+    fn rebase_merge_progress_names_the_rebased_branch() {
+        let fixture = Fixture::new("rebase-merge");
+        let onto = fixture.commit("file", "two\n");
+        fixture.write_git("rebase-merge/head-name", "refs/heads/feature\n");
+        fixture.write_git("rebase-merge/msgnum", "2\n");
+        fixture.write_git("rebase-merge/end", "5\n");
+        fixture.write_git("rebase-merge/onto", &format!("{}\n", onto));
+
+        let rebase = get_rebase(&fixture.repo).unwrap();
+        assert_eq!(rebase.operation, "REBASE");
+        assert_eq!(rebase.branch.as_deref(), Some("feature"));
+        assert_eq!(rebase.onto.as_deref(), Some("main"));
+        assert_eq!(rebase.label(), "|REBASE 2/5");
+
+        // Rebasing a detached HEAD records no branch
+        fixture.write_git("rebase-merge/head-name", "detached HEAD\n");
+        assert_eq!(get_rebase(&fixture.repo).unwrap().branch, None);
+    }
+
+    #[test]
+    fn rebase_apply_tells_am_from_rebase() {
+        let fixture = Fixture::new("rebase-apply");
+        fixture.write_git("rebase-apply/next", "3\n");
+        fixture.write_git("rebase-apply/last", "10\n");
+        fixture.write_git("rebase-apply/head-name", "refs/heads/topic\n");
+        fixture.write_git("rebase-apply/rebasing", "");
+        let rebase = get_rebase(&fixture.repo).unwrap();
+        assert_eq!(rebase.label(), "|REBASE 3/10");
+        assert_eq!(rebase.branch.as_deref(), Some("topic"));
+
+        fs::remove_file(fixture.repo.path().join("rebase-apply/rebasing")).unwrap();
+        fixture.write_git("rebase-apply/applying", "");
+        assert_eq!(get_rebase(&fixture.repo).unwrap().label(), "|AM 3/10");
+    }
+
+    #[test]
+    fn upstream_without_a_remote_tracking_ref_is_gone() {
+        let fixture = Fixture::new("gone");
+        let repo = &fixture.repo;
+        let head = || repo.head().unwrap();
+        assert!(!upstream_is_gone(repo, &head()));
+
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("branch.main.remote", "origin").unwrap();
+        config
+            .set_str("branch.main.merge", "refs/heads/main")
+            .unwrap();
+        assert!(upstream_is_gone(repo, &head()));
+
+        let id = head().target().unwrap();
+        repo.reference("refs/remotes/origin/main", id, false, "fetch")
+            .unwrap();
+        assert!(!upstream_is_gone(repo, &head()));
+    }
+
+    #[test]
+    fn untracked_mode_follows_show_untracked_files() {
+        let fixture = Fixture::new("untracked-mode");
+        let mut options = Options::default();
+        assert_eq!(get_untracked_mode(&fixture.repo, &options), "no");
+
+        let mut config = fixture.repo.config().unwrap();
+        for (value, mode) in [("normal", "normal"), ("True", "normal"), ("all", "all")] {
+            config.set_str("status.showUntrackedFiles", value).unwrap();
+            assert_eq!(get_untracked_mode(&fixture.repo, &options), mode);
+        }
+        config.set_str("status.showUntrackedFiles", "no").unwrap();
+        assert_eq!(get_untracked_mode(&fixture.repo, &options), "no");
+
+        // -u wins over the configuration
+        options.untracked_files = Some("all".to_string());
+        assert_eq!(get_untracked_mode(&fixture.repo, &options), "all");
     }
-    if deleted > 0 {
-        message += &format!("x{}", deleted);
+
+    #[test]
+    fn assume_unchanged_changes_are_counted_from_anywhere_in_the_tree() {
+        let fixture = Fixture::new("assume-unchanged");
+        fixture.commit("hidden", "one\n");
+        fs::create_dir(fixture.path("sub")).unwrap();
+        fixture.commit("sub/gone", "one\n");
+        fixture.commit("sparse", "one\n");
+        for args in [
+            &["--assume-unchanged", "hidden", "sub/gone"][..],
+            &["--skip-worktree", "sparse"],
+        ] {
+            let status = git_command()
+                .arg("update-index")
+                .args(args)
+                .current_dir(&fixture.dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        fixture.write("hidden", "two\n");
+        fs::remove_file(fixture.path("sub/gone")).unwrap();
+        // Absent by design, as outside a sparse checkout
+        fs::remove_file(fixture.path("sparse")).unwrap();
+
+        for dir in [fixture.dir.clone(), fixture.path("sub")] {
+            let mut summary = ChangesSummary::default();
+            in_dir(&dir, || {
+                count_hidden_changes(&fixture.repo, &mut summary, &Options::default())
+            });
+            assert_eq!((summary.updated, summary.deleted), (1, 1));
+            assert_eq!(summary.unstaged, 2);
+        }
     }
-    if untracked > 0 {
-        message += &format!("n{}", untracked);
+
+    #[test]
+    fn state_names_the_operation_in_progress() {
+        let fixture = Fixture::new("state");
+        let head = fixture.repo.head().unwrap().target().unwrap().to_string();
+        assert_eq!(get_state(&fixture.repo, false), "");
+        assert_eq!(get_state(&fixture.repo, true), "detached");
+
+        let cases = [
+            ("MERGE_HEAD", "merge"),
+            ("CHERRY_PICK_HEAD", "cherry-pick"),
+            ("REVERT_HEAD", "revert"),
+            ("BISECT_LOG", "bisect"),
+            ("rebase-merge/head-name", "rebase"),
+            ("rebase-apply/rebasing", "rebase"),
+            ("rebase-apply/applying", "am"),
+        ];
+        for (file, state) in cases {
+            fixture.write_git(file, &format!("{}\n", head));
+            // Operations detach HEAD themselves, and still win
+            assert_eq!(get_state(&fixture.repo, true), state, "{}", file);
+            fixture.repo.cleanup_state().unwrap();
+        }
     }
-    if message.is_empty() {
-        message = "✓".to_string();
+
+    #[test]
+    fn fast_clean_agrees_with_a_full_status() {
+        let fixture = Fixture::new("fast-clean");
+        let fast = Options {
+            fast_clean: true,
+            ..Options::default()
+        };
+        let status = |options: &Options| {
+            in_dir(&fixture.dir, || get_git_status(&fixture.repo, options)).unwrap()
+        };
+
+        let clean = status(&fast);
+        assert!(clean.fast_clean && clean.is_clean());
+        let full = status(&Options::default());
+        assert!(!full.fast_clean && full.is_clean());
+
+        fixture.write("file", "changed\n");
+        let dirty = status(&fast);
+        assert!(!dirty.fast_clean);
+        assert_eq!(dirty.updated, 1);
+        assert_eq!(status(&Options::default()).updated, 1);
     }
 
-    message
+    #[test]
+    fn paths_inside_the_git_dir_are_detected() {
+        let fixture = Fixture::new("inside-git-dir");
+        let (inside, work_tree) = in_dir(&fixture.repo.path().join("refs"), || {
+            let repo = Repository::open_from_env().unwrap();
+            (is_inside_git_dir(&repo), work_tree(&repo).unwrap())
+        });
+        assert!(inside);
+        assert_eq!(
+            fs::canonicalize(work_tree).unwrap(),
+            fs::canonicalize(&fixture.dir).unwrap()
+        );
+
+        fs::create_dir(fixture.path("sub")).unwrap();
+        for dir in [fixture.dir.clone(), fixture.path("sub")] {
+            assert!(!in_dir(&dir, || is_inside_git_dir(&fixture.repo)));
+        }
+    }
 }
@@ -0,0 +1,399 @@
+// Minimal `{token}` templating for `--format`; `{{` and `}}` produce literal braces.
+// `%(condition?body)` renders `body` only when the condition holds, and parentheses
+// inside the body are fine as long as they balance. Numeric tokens take an optional
+// width, `{updated:3}`, and are right-aligned to it with spaces. `{color:<name>}`
+// switches the terminal color when color output is enabled and renders nothing
+// otherwise; the names are the eight basic colors (black, red, green, yellow, blue,
+// magenta, cyan, white), bold, dim and reset. `{color:196}` picks from the 256-color
+// palette and `{color:#ff8800}` is a truecolor value, approximated from the palette on
+// terminals limited to 256 colors
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+// SGR parameters for `{color:<name>}`
+const COLORS: &[(&str, &str)] = &[
+    ("black", "30"),
+    ("red", "31"),
+    ("green", "32"),
+    ("yellow", "33"),
+    ("blue", "34"),
+    ("magenta", "35"),
+    ("cyan", "36"),
+    ("white", "37"),
+    ("bold", "1"),
+    ("dim", "2"),
+    ("reset", "0"),
+];
+
+// How much color the output may use
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    Off,
+    Ansi256,
+    TrueColor,
+}
+
+enum Color {
+    // SGR parameters of a named color
+    Named(&'static str),
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn parse(name: &str) -> Option<Color> {
+        if let Some((_, code)) = COLORS.iter().find(|(color, _)| *color == name) {
+            return Some(Color::Named(code));
+        }
+        if let Some(hex) = name.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return None;
+            }
+            let channel = |at: usize| u8::from_str_radix(&hex[at..at + 2], 16).ok();
+            return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+        }
+        name.parse().ok().map(Color::Indexed)
+    }
+
+    fn escape(&self, depth: ColorDepth) -> String {
+        match (self, depth) {
+            (_, ColorDepth::Off) => String::new(),
+            (Color::Named(code), _) => format!("\x1b[{}m", code),
+            (Color::Indexed(index), _) => format!("\x1b[38;5;{}m", index),
+            (Color::Rgb(r, g, b), ColorDepth::TrueColor) => {
+                format!("\x1b[38;2;{};{};{}m", r, g, b)
+            }
+            (Color::Rgb(r, g, b), ColorDepth::Ansi256) => {
+                format!("\x1b[38;5;{}m", nearest_palette_index(*r, *g, *b))
+            }
+        }
+    }
+}
+
+// The closest entry in the 6x6x6 color cube at 16-231, whose levels are 0, 95, 135,
+// 175, 215 and 255
+fn nearest_palette_index(r: u8, g: u8, b: u8) -> u8 {
+    let level = |channel: u8| match channel {
+        0..=47 => 0,
+        48..=114 => 1,
+        channel => (channel - 35) / 40,
+    };
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+// The names a template may refer to
+pub struct Vocabulary<'a> {
+    pub tokens: &'a [&'a str],
+    // The subset of `tokens` that accept a `:<width>` suffix
+    pub numeric_tokens: &'a [&'a str],
+    pub conditions: &'a [&'a str],
+}
+
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+enum Segment {
+    Literal(String),
+    Token {
+        name: String,
+        width: Option<usize>,
+    },
+    Conditional {
+        condition: String,
+        body: Vec<Segment>,
+    },
+    Color(Color),
+}
+
+impl Template {
+    // Names are validated up front against the vocabulary so typos fail loudly instead
+    // of silently rendering nothing in a prompt
+    pub fn parse(source: &str, vocabulary: &Vocabulary) -> Result<Template, String> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+            vocabulary,
+        };
+        let segments = parser.parse_segments(false)?;
+        Ok(Template { segments })
+    }
+
+    pub fn render(
+        &self,
+        lookup: &dyn Fn(&str) -> String,
+        condition: &dyn Fn(&str) -> bool,
+        color: ColorDepth,
+    ) -> String {
+        render_segments(&self.segments, lookup, condition, color)
+    }
+
+    // Whether any token or condition called `name` appears, so callers can skip
+    // computing values nobody asked for
+    pub fn uses(&self, name: &str) -> bool {
+        segments_use(&self.segments, name)
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    vocabulary: &'a Vocabulary<'a>,
+}
+
+impl Parser<'_> {
+    fn parse_segments(&mut self, in_body: bool) -> Result<Vec<Segment>, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut depth = 0;
+
+        while let Some(c) = self.chars.next() {
+            match c {
+                '{' if self.chars.peek() == Some(&'{') => {
+                    self.chars.next();
+                    literal.push('{');
+                }
+                '}' if self.chars.peek() == Some(&'}') => {
+                    self.chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let spec = self.read_until('}', "token")?;
+                    if let Some(("color", name)) = spec.split_once(':') {
+                        let color =
+                            Color::parse(name).ok_or_else(|| format!("unknown color {}", name))?;
+                        flush(&mut segments, &mut literal);
+                        segments.push(Segment::Color(color));
+                        continue;
+                    }
+                    let (name, width) = match spec.split_once(':') {
+                        Some((name, width)) => (name.to_string(), Some(width)),
+                        None => (spec.clone(), None),
+                    };
+                    if !self.vocabulary.tokens.contains(&name.as_str()) {
+                        return Err(format!("unknown token {{{}}}", name));
+                    }
+                    let width = match width {
+                        Some(_) if !self.vocabulary.numeric_tokens.contains(&name.as_str()) => {
+                            return Err(format!("{{{}}} is not numeric and takes no width", name));
+                        }
+                        Some(width) => Some(
+                            width
+                                .parse()
+                                .map_err(|_| format!("invalid width in {{{}}}", spec))?,
+                        ),
+                        None => None,
+                    };
+                    flush(&mut segments, &mut literal);
+                    segments.push(Segment::Token { name, width });
+                }
+                '}' => return Err("unmatched } (use }} for a literal brace)".to_string()),
+                '%' if self.chars.peek() == Some(&'(') => {
+                    self.chars.next();
+                    let condition = self.read_until('?', "condition")?;
+                    if !self.vocabulary.conditions.contains(&condition.as_str()) {
+                        return Err(format!("unknown condition {}", condition));
+                    }
+                    let body = self.parse_segments(true)?;
+                    flush(&mut segments, &mut literal);
+                    segments.push(Segment::Conditional { condition, body });
+                }
+                '(' if in_body => {
+                    depth += 1;
+                    literal.push(c);
+                }
+                ')' if in_body && depth == 0 => {
+                    flush(&mut segments, &mut literal);
+                    return Ok(segments);
+                }
+                ')' if in_body => {
+                    depth -= 1;
+                    literal.push(c);
+                }
+                c => literal.push(c),
+            }
+        }
+        if in_body {
+            return Err("unterminated %( section".to_string());
+        }
+        flush(&mut segments, &mut literal);
+
+        Ok(segments)
+    }
+
+    fn read_until(&mut self, end: char, what: &str) -> Result<String, String> {
+        let mut name = String::new();
+        loop {
+            match self.chars.next() {
+                Some(c) if c == end => return Ok(name),
+                Some(c) => name.push(c),
+                None => return Err(format!("unterminated {} {}", what, name)),
+            }
+        }
+    }
+}
+
+fn flush(segments: &mut Vec<Segment>, literal: &mut String) {
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(std::mem::take(literal)));
+    }
+}
+
+fn render_segments(
+    segments: &[Segment],
+    lookup: &dyn Fn(&str) -> String,
+    condition: &dyn Fn(&str) -> bool,
+    color: ColorDepth,
+) -> String {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Literal(text) => text.clone(),
+            Segment::Token { name, width } => match width {
+                Some(width) => format!("{:>width$}", lookup(name), width = width),
+                None => lookup(name),
+            },
+            Segment::Conditional {
+                condition: name,
+                body,
+            } => {
+                if condition(name) {
+                    render_segments(body, lookup, condition, color)
+                } else {
+                    String::new()
+                }
+            }
+            Segment::Color(value) => value.escape(color),
+        })
+        .collect()
+}
+
+fn segments_use(segments: &[Segment], name: &str) -> bool {
+    segments.iter().any(|segment| match segment {
+        Segment::Literal(_) | Segment::Color(_) => false,
+        Segment::Token { name: token, .. } => token == name,
+        Segment::Conditional { condition, body } => condition == name || segments_use(body, name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOCABULARY: Vocabulary = Vocabulary {
+        tokens: &["branch", "ahead"],
+        numeric_tokens: &["ahead"],
+        conditions: &["dirty", "clean"],
+    };
+
+    fn lookup(name: &str) -> String {
+        match name {
+            "branch" => "main".to_string(),
+            "ahead" => "12".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn render(source: &str) -> String {
+        render_colored(source, ColorDepth::Off)
+    }
+
+    fn render_colored(source: &str, color: ColorDepth) -> String {
+        let template = Template::parse(source, &VOCABULARY).unwrap();
+        template.render(&lookup, &|name| name == "dirty", color)
+    }
+
+    fn parse_error(source: &str) -> String {
+        match Template::parse(source, &VOCABULARY) {
+            Ok(_) => panic!("{} parsed", source),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn renders_tokens_and_literal_braces() {
+        assert_eq!(render("{branch} {{x}}"), "main {x}");
+    }
+
+    #[test]
+    fn conditional_body_follows_its_condition() {
+        assert_eq!(render("{branch}%(dirty? *)"), "main *");
+        assert_eq!(render("{branch}%(clean? ok)"), "main");
+    }
+
+    #[test]
+    fn balanced_parentheses_stay_in_the_body() {
+        assert_eq!(render("%(dirty?(+{ahead}))!"), "(+12)!");
+        assert_eq!(render("%(dirty?a%(clean?b)c)"), "ac");
+    }
+
+    #[test]
+    fn reports_unknown_names_and_unterminated_sections() {
+        assert_eq!(parse_error("{nope}"), "unknown token {nope}");
+        assert_eq!(parse_error("%(nope?x)"), "unknown condition nope");
+        assert_eq!(parse_error("%(dirty?x"), "unterminated %( section");
+        assert_eq!(parse_error("}"), "unmatched } (use }} for a literal brace)");
+    }
+
+    #[test]
+    fn numeric_tokens_are_right_aligned_to_their_width() {
+        assert_eq!(render("[{ahead:4}]"), "[  12]");
+        // Wider values are never cut
+        assert_eq!(render("[{ahead:1}]"), "[12]");
+    }
+
+    #[test]
+    fn widths_only_apply_to_numeric_tokens() {
+        assert_eq!(
+            parse_error("{branch:3}"),
+            "{branch} is not numeric and takes no width"
+        );
+        assert_eq!(parse_error("{ahead:x}"), "invalid width in {ahead:x}");
+    }
+
+    #[test]
+    fn named_colors_render_only_when_enabled() {
+        let source = "{color:red}{branch}{color:reset}";
+        assert_eq!(render(source), "main");
+        assert_eq!(
+            render_colored(source, ColorDepth::Ansi256),
+            "\x1b[31mmain\x1b[0m"
+        );
+        assert_eq!(parse_error("{color:mauve}"), "unknown color mauve");
+    }
+
+    #[test]
+    fn indexed_and_rgb_colors_follow_the_color_depth() {
+        assert_eq!(
+            render_colored("{color:196}", ColorDepth::Ansi256),
+            "\x1b[38;5;196m"
+        );
+        assert_eq!(
+            render_colored("{color:#ff8800}", ColorDepth::TrueColor),
+            "\x1b[38;2;255;136;0m"
+        );
+        assert_eq!(
+            render_colored("{color:#ff8800}", ColorDepth::Ansi256),
+            "\x1b[38;5;208m"
+        );
+        assert_eq!(render("{color:#ff8800}"), "");
+        assert_eq!(parse_error("{color:256}"), "unknown color 256");
+        assert_eq!(parse_error("{color:#ff88}"), "unknown color #ff88");
+    }
+
+    #[test]
+    fn rgb_maps_to_the_nearest_cube_level() {
+        assert_eq!(nearest_palette_index(0, 0, 0), 16);
+        assert_eq!(nearest_palette_index(255, 255, 255), 231);
+        // 114 is closer to 95, 115 to 135
+        assert_eq!(nearest_palette_index(114, 0, 0), 16 + 36);
+        assert_eq!(nearest_palette_index(115, 0, 0), 16 + 72);
+    }
+
+    #[test]
+    fn uses_looks_inside_conditionals() {
+        let template = Template::parse("%(dirty?{ahead})", &VOCABULARY).unwrap();
+        assert!(template.uses("dirty"));
+        assert!(template.uses("ahead"));
+        assert!(!template.uses("branch"));
+    }
+}
@@ -0,0 +1,224 @@
+// Terminal display width of rendered output. ANSI escape sequences take no columns,
+// combining marks and zero-width characters take none, and East Asian wide characters
+// and emoji take two, which is what prompt frameworks need to pad correctly
+
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            read_escape(&mut chars);
+            continue;
+        }
+        width += char_width(c);
+    }
+
+    width
+}
+
+// Cuts `text` down to `max` columns, ending in `ellipsis` when anything was dropped and
+// it fits. Escape sequences are copied whole rather than split, and a reset follows the
+// ellipsis if a color was switched on so it can't leak into whatever comes next
+pub fn truncate(text: &str, max: usize, ellipsis: &str) -> String {
+    if display_width(text) <= max {
+        return text.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+    let ellipsis = if display_width(ellipsis) <= max {
+        ellipsis
+    } else {
+        ""
+    };
+    let budget = max - display_width(ellipsis);
+
+    let mut truncated = String::new();
+    let mut width = 0;
+    let mut colored = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            truncated.push(c);
+            truncated.push_str(&read_escape(&mut chars));
+            colored = true;
+            continue;
+        }
+        let char_width = char_width(c);
+        if width + char_width > budget {
+            break;
+        }
+        width += char_width;
+        truncated.push(c);
+    }
+    truncated.push_str(ellipsis);
+    if colored {
+        truncated.push_str("\x1b[0m");
+    }
+
+    truncated
+}
+
+// Consumes and returns the rest of a CSI sequence (`ESC [ ... final`) or a two-byte
+// escape
+fn read_escape(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut sequence = String::new();
+    match chars.next() {
+        Some('[') => sequence.push('['),
+        Some(c) => {
+            sequence.push(c);
+            return sequence;
+        }
+        None => return sequence,
+    }
+    for c in chars.by_ref() {
+        sequence.push(c);
+        if ('\x40'..='\x7e').contains(&c) {
+            break;
+        }
+    }
+
+    sequence
+}
+
+fn char_width(c: char) -> usize {
+    let code = c as u32;
+    if c.is_control() || is_zero_width(code) {
+        0
+    } else if is_wide(code) {
+        2
+    } else {
+        1
+    }
+}
+
+fn is_zero_width(code: u32) -> bool {
+    matches!(code,
+        0x0300..=0x036f // combining diacritical marks
+        | 0x200b..=0x200f // zero-width space, joiners and direction marks
+        | 0x20d0..=0x20ff // combining marks for symbols
+        | 0xfe00..=0xfe0f // variation selectors
+        | 0xfe20..=0xfe2f // combining half marks
+    )
+}
+
+// East Asian Wide and Fullwidth ranges from EastAsianWidth.txt, which is also where
+// emoji with default emoji presentation get their two columns
+fn is_wide(code: u32) -> bool {
+    matches!(code,
+        0x1100..=0x115f // Hangul Jamo
+        | 0x231a..=0x231b // watch, hourglass
+        | 0x2329..=0x232a
+        | 0x23e9..=0x23ec
+        | 0x23f0
+        | 0x23f3
+        | 0x25fd..=0x25fe
+        | 0x2614..=0x2615
+        | 0x2648..=0x2653 // zodiac signs
+        | 0x267f
+        | 0x2693
+        | 0x26a1
+        | 0x26aa..=0x26ab
+        | 0x26bd..=0x26be
+        | 0x26c4..=0x26c5
+        | 0x26ce
+        | 0x26d4
+        | 0x26ea
+        | 0x26f2..=0x26f3
+        | 0x26f5
+        | 0x26fa
+        | 0x26fd
+        | 0x2705 // check mark button
+        | 0x270a..=0x270b
+        | 0x2728
+        | 0x274c // cross mark
+        | 0x274e
+        | 0x2753..=0x2755
+        | 0x2757
+        | 0x2795..=0x2797
+        | 0x27b0
+        | 0x27bf
+        | 0x2b1b..=0x2b1c
+        | 0x2b50 // star
+        | 0x2b55
+        | 0x2e80..=0x303e // CJK radicals, punctuation
+        | 0x3041..=0x33ff // kana, CJK symbols
+        | 0x3400..=0x4dbf // CJK extension A
+        | 0x4e00..=0x9fff // CJK unified ideographs
+        | 0xa000..=0xa4cf // Yi
+        | 0xac00..=0xd7a3 // Hangul syllables
+        | 0xf900..=0xfaff // CJK compatibility ideographs
+        | 0xfe30..=0xfe4f // CJK compatibility forms
+        | 0xff00..=0xff60 // fullwidth forms
+        | 0xffe0..=0xffe6
+        | 0x16fe0..=0x16fe4
+        | 0x17000..=0x18cd5 // Tangut, Khitan
+        | 0x1b000..=0x1b2fb // kana supplement and extensions, Nushu
+        | 0x1f004
+        | 0x1f0cf
+        | 0x1f18e
+        | 0x1f191..=0x1f19a
+        | 0x1f200..=0x1f202
+        | 0x1f210..=0x1f23b
+        | 0x1f240..=0x1f248
+        | 0x1f250..=0x1f251
+        | 0x1f260..=0x1f265
+        | 0x1f300..=0x1f64f // pictographs and emoticons
+        | 0x1f680..=0x1f6ff // transport and map symbols
+        | 0x1f7e0..=0x1f7eb // colored circles and squares
+        | 0x1f7f0
+        | 0x1f900..=0x1f9ff // supplemental pictographs
+        | 0x1fa70..=0x1faff // symbols and pictographs extended-A
+        | 0x20000..=0x3fffd // CJK extensions B and beyond
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_sequences_take_no_columns() {
+        assert_eq!(display_width("\x1b[31mmain\x1b[0m ✓"), 6);
+        assert_eq!(display_width("\x1b[38;5;208mx"), 1);
+    }
+
+    #[test]
+    fn wide_and_combining_characters() {
+        assert_eq!(display_width("日本"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("🚀"), 2);
+        assert_eq!(display_width("✅"), 2);
+        assert_eq!(display_width("🟠"), 2);
+        assert_eq!(display_width("⚡⭐"), 4);
+        // Text-presentation symbols stay narrow
+        assert_eq!(display_width("✓✂"), 2);
+    }
+
+    #[test]
+    fn truncate_leaves_fitting_text_alone() {
+        assert_eq!(truncate("main ✓", 6, "…"), "main ✓");
+    }
+
+    #[test]
+    fn truncate_keeps_escapes_whole_and_resets_color() {
+        assert_eq!(
+            truncate("\x1b[31mmain\x1b[0m ✓", 3, "…"),
+            "\x1b[31mma…\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn truncate_never_splits_a_wide_character() {
+        assert_eq!(truncate("日本語", 4, "…"), "日…");
+        assert_eq!(truncate("e\u{301}xyz", 2, "…"), "e\u{301}…");
+    }
+
+    #[test]
+    fn truncate_drops_an_ellipsis_that_does_not_fit() {
+        assert_eq!(truncate("abcdef", 4, "..."), "a...");
+        assert_eq!(truncate("abcdef", 2, "..."), "ab");
+        assert_eq!(truncate("abcdef", 0, "…"), "");
+    }
+}